@@ -0,0 +1,693 @@
+pub mod errors;
+pub mod krpc;
+pub mod routing;
+pub mod store;
+
+use crate::encoding::types::{BTypes, DictInner};
+use errors::DhtError;
+use krpc::*;
+use routing::{K, RoutingTable};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+use store::{PeerStore, TokenSecret};
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+
+/// Well known routers used to join the network when no other nodes are known.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Queries in flight at once during a lookup.
+const ALPHA: usize = 3;
+
+/// Receive buffer, larger than any KRPC message we expect.
+const MAX_DATAGRAM: usize = 1 << 16;
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// Address of the DHT socket, its port is what peers learn from `PeerMessage::Port`.
+    pub bind: SocketAddr,
+
+    /// `host:port` pairs contacted when bootstrapping.
+    pub bootstrap: Vec<String>,
+
+    /// Node id and routing table are loaded from here on start and written by `Dht::save`.
+    pub state_path: Option<PathBuf>,
+
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:6881".parse().unwrap(),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|h| h.to_string()).collect(),
+            state_path: None,
+            query_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A mainline DHT node (BEP 5).
+/// Clones share the same node, which answers queries in the background until the last clone is dropped.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<DhtInner>,
+}
+
+struct DhtInner {
+    socket: Arc<UdpSocket>,
+    config: DhtConfig,
+    state: Mutex<DhtState>,
+    pending: Mutex<HashMap<Vec<u8>, Pending>>,
+    transaction: AtomicU16,
+    task: OnceLock<JoinHandle<()>>,
+}
+
+struct DhtState {
+    routing: RoutingTable,
+    tokens: TokenSecret,
+    peers: PeerStore,
+
+    /// Nodes of the last session, only trusted once they answer during bootstrap.
+    saved: Vec<NodeInfo>,
+}
+
+/// Query waiting for its response.
+struct Pending {
+    addr: SocketAddr,
+    reply: oneshot::Sender<KrpcBody>,
+}
+
+/// Nodes that answered a lookup, with the write token they gave, and the peers they returned.
+#[derive(Default)]
+struct Lookup {
+    nodes: BTreeMap<[u8; 20], (NodeInfo, Option<Vec<u8>>)>,
+    values: BTreeSet<SocketAddr>,
+}
+
+impl Drop for DhtInner {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get() {
+            task.abort();
+        }
+    }
+}
+
+impl Dht {
+    /// Binds the socket and starts answering queries, reusing the saved node id if there is one.
+    /// Call `bootstrap` to join the network.
+    pub async fn bind(config: DhtConfig) -> Result<Self, DhtError> {
+        let (id, saved) = config
+            .state_path
+            .as_deref()
+            .and_then(load_state)
+            .unwrap_or_else(|| (NodeId::random(), Vec::new()));
+
+        let socket = Arc::new(UdpSocket::bind(config.bind).await?);
+
+        let inner = Arc::new(DhtInner {
+            socket: socket.clone(),
+            config,
+            state: Mutex::new(DhtState {
+                routing: RoutingTable::new(id),
+                tokens: TokenSecret::new(),
+                peers: PeerStore::default(),
+                saved,
+            }),
+            pending: Mutex::new(HashMap::new()),
+            transaction: AtomicU16::new(rand::random()),
+            task: OnceLock::new(),
+        });
+
+        let task = tokio::spawn(receive_loop(socket, Arc::downgrade(&inner)));
+        let _ = inner.task.set(task);
+
+        Ok(Self { inner })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.state.lock().unwrap().routing.own_id()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Good and questionable nodes of the routing table.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.inner.state.lock().unwrap().routing.nodes()
+    }
+
+    /// Joins the network through the configured bootstrap nodes and the nodes saved by the last session,
+    /// then fills the routing table with a lookup of our own id.
+    /// Returns the size of the routing table.
+    pub async fn bootstrap(&self) -> Result<usize, DhtError> {
+        let own = self.id();
+
+        let mut seeds = std::mem::take(&mut self.inner.state.lock().unwrap().saved)
+            .into_iter()
+            .map(|n| n.addr)
+            .collect::<Vec<_>>();
+
+        // Unresolvable routers are skipped, the others may still work
+        for host in &self.inner.config.bootstrap {
+            if let Ok(addrs) = lookup_host(host.as_str()).await {
+                seeds.extend(addrs);
+            }
+        }
+
+        let mut queries = JoinSet::new();
+
+        for addr in seeds {
+            let dht = self.clone();
+            queries.spawn(async move { dht.find_node(addr, own).await });
+        }
+
+        let start = queries
+            .join_all()
+            .await
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect();
+
+        self.lookup(own, false, start).await;
+
+        match self.inner.state.lock().unwrap().routing.len() {
+            0 => Err(DhtError::NoNodes),
+            n => Ok(n),
+        }
+    }
+
+    /// Returns the id of the node at `addr`, which is added to the routing table if it answers.
+    pub async fn ping(&self, addr: SocketAddr) -> Result<NodeId, DhtError> {
+        let query = Query::Ping { id: self.id() };
+
+        Ok(self.query(addr, query).await?.id)
+    }
+
+    /// Nodes `addr` knows closest to `target`.
+    pub async fn find_node(
+        &self,
+        addr: SocketAddr,
+        target: NodeId,
+    ) -> Result<Vec<NodeInfo>, DhtError> {
+        let query = Query::FindNode {
+            id: self.id(),
+            target,
+        };
+
+        Ok(self.query(addr, query).await?.nodes)
+    }
+
+    /// The `K` nodes of the network closest to `target`.
+    pub async fn find_closest(&self, target: NodeId) -> Vec<NodeInfo> {
+        let lookup = self.lookup(target, false, Vec::new()).await;
+
+        lookup.nodes.into_values().take(K).map(|(n, _)| n).collect()
+    }
+
+    /// Peers of a torrent, from the nodes closest to its info hash and our own store.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        let info_hash = NodeId(info_hash);
+        let lookup = self.lookup(info_hash, true, Vec::new()).await;
+
+        let mut peers = self.inner.state.lock().unwrap().peers.get(&info_hash);
+
+        for peer in lookup.values {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+
+        peers
+    }
+
+    /// Announces that we accept peers of a torrent on `port` to the nodes closest to its info hash,
+    /// returns the peers found on the way.
+    pub async fn announce(
+        &self,
+        info_hash: [u8; 20],
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, DhtError> {
+        let own = self.id();
+        let lookup = self.lookup(NodeId(info_hash), true, Vec::new()).await;
+
+        let mut announces = JoinSet::new();
+
+        for (node, token) in lookup.nodes.into_values().take(K) {
+            let Some(token) = token else {
+                continue;
+            };
+
+            let query = Query::AnnouncePeer {
+                id: own,
+                info_hash: NodeId(info_hash),
+                port,
+                implied_port: false,
+                token,
+            };
+
+            let dht = self.clone();
+            announces.spawn(async move { dht.query(node.addr, query).await });
+        }
+
+        let accepted = announces
+            .join_all()
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count();
+
+        if accepted == 0 {
+            return Err(DhtError::NoNodes);
+        }
+
+        Ok(lookup.values.into_iter().collect())
+    }
+
+    /// Writes the node id and routing table to `state_path`, if one is configured.
+    pub fn save(&self) -> Result<(), DhtError> {
+        let Some(path) = &self.inner.config.state_path else {
+            return Ok(());
+        };
+
+        let (id, nodes) = {
+            let state = self.inner.state.lock().unwrap();
+            (state.routing.own_id(), state.routing.nodes())
+        };
+
+        let mut dict: DictInner = BTreeMap::new();
+        dict.insert("id".into(), BTypes::ByteString(id.0.to_vec()));
+        dict.insert(
+            "nodes".into(),
+            BTypes::ByteString(encode_nodes(&nodes, false)),
+        );
+        dict.insert(
+            "nodes6".into(),
+            BTypes::ByteString(encode_nodes(&nodes, true)),
+        );
+
+        std::fs::write(path, BTypes::Dict(dict).bencode())?;
+
+        Ok(())
+    }
+
+    async fn query(&self, addr: SocketAddr, query: Query) -> Result<Response, DhtError> {
+        let transaction = self
+            .inner
+            .transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+
+        let (reply, receiver) = oneshot::channel();
+
+        self.inner
+            .pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), Pending { addr, reply });
+
+        let message = KrpcMessage {
+            transaction: transaction.clone(),
+            body: KrpcBody::Query(query),
+        };
+
+        if let Err(e) = self.inner.socket.send_to(&message.encode(), addr).await {
+            self.inner.pending.lock().unwrap().remove(&transaction);
+            return Err(e.into());
+        }
+
+        match timeout(self.inner.config.query_timeout, receiver).await {
+            Ok(Ok(KrpcBody::Response(response))) => Ok(response),
+            Ok(Ok(KrpcBody::Error { code, message })) => Err(DhtError::Remote { code, message }),
+            _ => {
+                self.inner.pending.lock().unwrap().remove(&transaction);
+                self.inner.state.lock().unwrap().routing.failed(&addr);
+
+                Err(DhtError::Timeout)
+            }
+        }
+    }
+
+    /// Iterative lookup of the nodes closest to `target`, starting from the routing table and `start`.
+    /// Each round asks up to `ALPHA` of the `K` closest nodes not asked yet, it ends when all of them answered or failed.
+    async fn lookup(&self, target: NodeId, get_peers: bool, start: Vec<NodeInfo>) -> Lookup {
+        let own = self.id();
+
+        let mut candidates = self
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .routing
+            .closest(&target, K)
+            .into_iter()
+            .chain(start)
+            .filter(|n| n.id != own)
+            .map(|n| (n.id.distance(&target), n))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut queried = HashSet::new();
+        let mut lookup = Lookup::default();
+
+        loop {
+            let next = candidates
+                .values()
+                .take(K)
+                .filter(|n| !queried.contains(&n.addr))
+                .take(ALPHA)
+                .copied()
+                .collect::<Vec<_>>();
+
+            if next.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+
+            for node in next {
+                queried.insert(node.addr);
+
+                let query = if get_peers {
+                    Query::GetPeers {
+                        id: own,
+                        info_hash: target,
+                    }
+                } else {
+                    Query::FindNode { id: own, target }
+                };
+
+                let dht = self.clone();
+                queries.spawn(async move { (node, dht.query(node.addr, query).await) });
+            }
+
+            while let Some(Ok((node, response))) = queries.join_next().await {
+                let distance = node.id.distance(&target);
+
+                let Ok(response) = response else {
+                    candidates.remove(&distance);
+                    continue;
+                };
+
+                for found in response.nodes.into_iter().filter(|n| n.id != own) {
+                    candidates
+                        .entry(found.id.distance(&target))
+                        .or_insert(found);
+                }
+
+                lookup.values.extend(response.values);
+                lookup.nodes.insert(distance, (node, response.token));
+            }
+        }
+
+        lookup
+    }
+}
+
+impl DhtInner {
+    fn handle_query(&self, query: Query, from: SocketAddr) -> KrpcBody {
+        let mut state = self.state.lock().unwrap();
+
+        state.routing.insert(NodeInfo {
+            id: query.id(),
+            addr: from,
+        });
+
+        let mut response = Response::new(state.routing.own_id());
+
+        match query {
+            Query::Ping { .. } => {}
+            Query::FindNode { target, .. } => {
+                response.nodes = state.routing.closest(&target, K);
+            }
+            Query::GetPeers { info_hash, .. } => {
+                response.token = Some(state.tokens.token(from.ip()));
+                response.values = state.peers.get(&info_hash);
+
+                if response.values.is_empty() {
+                    response.nodes = state.routing.closest(&info_hash, K);
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                ..
+            } => {
+                if !state.tokens.verify(from.ip(), &token) {
+                    return KrpcBody::Error {
+                        code: ERROR_PROTOCOL,
+                        message: "Bad token".to_owned(),
+                    };
+                }
+
+                let port = if implied_port { from.port() } else { port };
+
+                state
+                    .peers
+                    .insert(info_hash, SocketAddr::new(from.ip(), port));
+            }
+            Query::Unknown { .. } => {
+                return KrpcBody::Error {
+                    code: ERROR_METHOD_UNKNOWN,
+                    message: "Method Unknown".to_owned(),
+                };
+            }
+        }
+
+        KrpcBody::Response(response)
+    }
+
+    /// Hands a response or error to the query waiting for it, replies from anyone but the queried node are ignored.
+    fn handle_reply(&self, transaction: Vec<u8>, body: KrpcBody, from: SocketAddr) {
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+
+            if pending.get(&transaction).is_none_or(|p| p.addr != from) {
+                return;
+            }
+
+            pending.remove(&transaction)
+        };
+
+        if let KrpcBody::Response(response) = &body {
+            self.state.lock().unwrap().routing.insert(NodeInfo {
+                id: response.id,
+                addr: from,
+            });
+        }
+
+        if let Some(pending) = pending {
+            let _ = pending.reply.send(body);
+        }
+    }
+}
+
+/// Answers queries and dispatches replies until the node is dropped.
+async fn receive_loop(socket: Arc<UdpSocket>, dht: Weak<DhtInner>) {
+    let mut buffer = vec![0; MAX_DATAGRAM];
+
+    loop {
+        // Errors here are per datagram, e.g. an ICMP unreachable for an earlier query
+        let Ok((len, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+
+        let Some(dht) = dht.upgrade() else {
+            return;
+        };
+
+        let Ok(message) = KrpcMessage::decode(&buffer[..len]) else {
+            continue;
+        };
+
+        match message.body {
+            KrpcBody::Query(query) => {
+                let reply = KrpcMessage {
+                    transaction: message.transaction,
+                    body: dht.handle_query(query, from),
+                };
+
+                let _ = socket.send_to(&reply.encode(), from).await;
+            }
+            body => dht.handle_reply(message.transaction, body, from),
+        }
+    }
+}
+
+/// Node id and nodes of a state file, a missing or corrupt file means starting afresh.
+fn load_state(path: &Path) -> Option<(NodeId, Vec<NodeInfo>)> {
+    let state = BTypes::bdecode(&std::fs::read(path).ok()?).ok()?;
+
+    let (id, state) = state.keyed_bytes("id").ok()?;
+    let (nodes, state) = state.keyed_optional("nodes", BTypes::keyed_bytes).ok()?;
+    let (nodes6, _) = state.keyed_optional("nodes6", BTypes::keyed_bytes).ok()?;
+
+    let mut saved = decode_nodes(&nodes.unwrap_or_default(), false)?;
+    saved.extend(decode_nodes(&nodes6.unwrap_or_default(), true)?);
+
+    Some((NodeId(id.try_into().ok()?), saved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_config(bootstrap: &[SocketAddr]) -> DhtConfig {
+        DhtConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: bootstrap.iter().map(|a| a.to_string()).collect(),
+            state_path: None,
+            // Generous for localhost, test runs on a loaded single core have seen replies take over 500 ms
+            query_timeout: Duration::from_secs(3),
+        }
+    }
+
+    async fn swarm(size: usize) -> Vec<Dht> {
+        let first = Dht::bind(local_config(&[])).await.unwrap();
+        let router = first.local_addr().unwrap();
+
+        let mut nodes = vec![first];
+
+        for _ in 1..size {
+            let node = Dht::bind(local_config(&[router])).await.unwrap();
+            node.bootstrap().await.unwrap();
+            nodes.push(node);
+        }
+
+        nodes
+    }
+
+    #[tokio::test]
+    async fn dht_ping() {
+        let nodes = swarm(2).await;
+
+        let id = nodes[1].ping(nodes[0].local_addr().unwrap()).await.unwrap();
+        assert_eq!(id, nodes[0].id());
+
+        // Both sides learnt about each other
+        assert_eq!(nodes[0].nodes()[0].id, nodes[1].id());
+        assert_eq!(nodes[1].nodes()[0].id, nodes[0].id());
+    }
+
+    #[tokio::test]
+    async fn dht_find_closest() {
+        let nodes = swarm(6).await;
+
+        let target = nodes[3].id();
+        let closest = nodes[5].find_closest(target).await;
+
+        assert_eq!(closest[0].id, target);
+        assert_eq!(closest.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn dht_announce_get_peers() {
+        let nodes = swarm(5).await;
+        let info_hash = [7; 20];
+
+        assert!(nodes[4].get_peers(info_hash).await.is_empty());
+
+        nodes[1].announce(info_hash, 51413).await.unwrap();
+
+        let peers = nodes[4].get_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:51413".parse().unwrap()]);
+
+        // Announcing again reports the peers found on the way
+        let peers = nodes[2].announce(info_hash, 6881).await.unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:51413".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn dht_bad_token() {
+        let nodes = swarm(2).await;
+
+        let query = Query::AnnouncePeer {
+            id: nodes[1].id(),
+            info_hash: NodeId([7; 20]),
+            port: 6881,
+            implied_port: false,
+            token: b"forged".to_vec(),
+        };
+
+        let result = nodes[1].query(nodes[0].local_addr().unwrap(), query).await;
+        assert!(matches!(
+            result,
+            Err(DhtError::Remote {
+                code: ERROR_PROTOCOL,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn dht_timeout() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let node = Dht::bind(DhtConfig {
+            query_timeout: Duration::from_millis(200),
+            ..local_config(&[])
+        })
+        .await
+        .unwrap();
+
+        let result = node.ping(silent.local_addr().unwrap()).await;
+        assert!(matches!(result, Err(DhtError::Timeout)));
+
+        assert!(matches!(node.bootstrap().await, Err(DhtError::NoNodes)));
+    }
+
+    #[tokio::test]
+    async fn dht_persistence() {
+        let path = std::env::temp_dir().join(format!("tc-dht-{}.state", rand::random::<u64>()));
+        let nodes = swarm(2).await;
+
+        let config = DhtConfig {
+            state_path: Some(path.clone()),
+            ..local_config(&[nodes[0].local_addr().unwrap()])
+        };
+
+        let node = Dht::bind(config.clone()).await.unwrap();
+        node.bootstrap().await.unwrap();
+        node.save().unwrap();
+
+        let id = node.id();
+        let known = node.nodes().len();
+        drop(node);
+
+        // Saved nodes are enough to bootstrap
+        let restarted = Dht::bind(DhtConfig {
+            bootstrap: Vec::new(),
+            ..config
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(restarted.id(), id);
+        assert_eq!(restarted.bootstrap().await.unwrap(), known);
+
+        std::fs::write(&path, b"garbage").unwrap();
+        let fresh = Dht::bind(DhtConfig {
+            state_path: Some(path.clone()),
+            ..local_config(&[])
+        })
+        .await
+        .unwrap();
+        assert_ne!(fresh.id(), id);
+        assert!(fresh.nodes().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
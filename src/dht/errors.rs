@@ -0,0 +1,52 @@
+use crate::encoding::errors::BencodingError;
+
+#[derive(Debug)]
+pub enum DhtError {
+    /// Socket level failure, or the state file could not be read or written.
+    Io(std::io::Error),
+    /// Message was not the bencoding we expected.
+    Decode(BencodingError),
+    /// Message did not match the KRPC layout.
+    Malformed(&'static str),
+    /// Node answered a query with a KRPC error.
+    Remote { code: isize, message: String },
+    /// Node did not answer within the query timeout.
+    Timeout,
+    /// Bootstrapping left the routing table empty.
+    NoNodes,
+}
+
+impl std::fmt::Display for DhtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DhtError::Io(e) => write!(f, "DHT socket error: {e}"),
+            DhtError::Decode(e) => write!(f, "Could not decode DHT message: {e}"),
+            DhtError::Malformed(m) => write!(f, "Malformed DHT message: {m}"),
+            DhtError::Remote { code, message } => write!(f, "DHT node error {code}: {message}"),
+            DhtError::Timeout => write!(f, "DHT node did not respond"),
+            DhtError::NoNodes => write!(f, "No DHT nodes reachable"),
+        }
+    }
+}
+
+impl std::error::Error for DhtError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DhtError::Io(e) => Some(e),
+            DhtError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DhtError {
+    fn from(value: std::io::Error) -> Self {
+        DhtError::Io(value)
+    }
+}
+
+impl From<BencodingError> for DhtError {
+    fn from(value: BencodingError) -> Self {
+        DhtError::Decode(value)
+    }
+}
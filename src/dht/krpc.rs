@@ -0,0 +1,506 @@
+use super::errors::DhtError;
+use crate::encoding::types::{BTypes, DictInner};
+use crate::tracker::{COMPACT_PEER_LEN, COMPACT_PEER6_LEN, decode_compact, encode_compact};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// Size of a compact node entry, node id followed by a compact IPv4 peer.
+pub const COMPACT_NODE_LEN: usize = 20 + COMPACT_PEER_LEN;
+
+/// Size of a compact node entry, node id followed by a compact IPv6 peer.
+pub const COMPACT_NODE6_LEN: usize = 20 + COMPACT_PEER6_LEN;
+
+pub const ERROR_GENERIC: isize = 201;
+pub const ERROR_SERVER: isize = 202;
+pub const ERROR_PROTOCOL: isize = 203;
+pub const ERROR_METHOD_UNKNOWN: isize = 204;
+
+/// 160 bit identifier shared by nodes and info hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// XOR metric, compares like a big endian integer.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DhtError> {
+        match <[u8; 20]>::try_from(bytes) {
+            Ok(id) => Ok(Self(id)),
+            Err(_) => Err(DhtError::Malformed("Node id is not 20 bytes")),
+        }
+    }
+}
+
+/// Contact information of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+/// Compact node info of the nodes in one address family, the other family is skipped.
+pub fn encode_nodes(nodes: &[NodeInfo], ipv6: bool) -> Vec<u8> {
+    nodes
+        .iter()
+        .filter(|n| n.addr.is_ipv6() == ipv6)
+        .flat_map(|n| n.id.0.into_iter().chain(encode_compact(&n.addr)))
+        .collect()
+}
+
+pub fn decode_nodes(compact: &[u8], ipv6: bool) -> Option<Vec<NodeInfo>> {
+    let entry_len = if ipv6 {
+        COMPACT_NODE6_LEN
+    } else {
+        COMPACT_NODE_LEN
+    };
+
+    if !compact.len().is_multiple_of(entry_len) {
+        return None;
+    }
+
+    compact
+        .chunks_exact(entry_len)
+        .map(|entry| {
+            let (id, peer) = entry.split_at(20);
+            let addr = decode_compact(peer, entry_len - 20)?.pop()?;
+
+            Some(NodeInfo {
+                id: NodeId(id.try_into().ok()?),
+                addr,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+    },
+    GetPeers {
+        id: NodeId,
+        info_hash: NodeId,
+    },
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: NodeId,
+        port: u16,
+        /// Use the port the query came from instead of `port`, for peers behind NAT.
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    /// Method we do not implement, answered with `ERROR_METHOD_UNKNOWN`.
+    Unknown {
+        id: NodeId,
+        method: String,
+    },
+}
+
+impl Query {
+    pub fn id(&self) -> NodeId {
+        match self {
+            Self::Ping { id }
+            | Self::FindNode { id, .. }
+            | Self::GetPeers { id, .. }
+            | Self::AnnouncePeer { id, .. }
+            | Self::Unknown { id, .. } => *id,
+        }
+    }
+}
+
+/// Response to any query, which keys are set depends on the query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+
+    /// Closest nodes we know, for `find_node` and `get_peers`.
+    pub nodes: Vec<NodeInfo>,
+
+    /// Peers of the torrent, for `get_peers`.
+    pub values: Vec<SocketAddr>,
+
+    /// Write token for a later `announce_peer`, for `get_peers`.
+    pub token: Option<Vec<u8>>,
+}
+
+impl Response {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            nodes: Vec::new(),
+            values: Vec::new(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KrpcBody {
+    Query(Query),
+    Response(Response),
+    Error { code: isize, message: String },
+}
+
+/// A single KRPC message, every datagram carries exactly one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcMessage {
+    /// Echoed in the response so queries and responses can be matched.
+    pub transaction: Vec<u8>,
+    pub body: KrpcBody,
+}
+
+impl KrpcMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict: DictInner = BTreeMap::new();
+
+        dict.insert("t".into(), BTypes::ByteString(self.transaction.clone()));
+
+        match &self.body {
+            KrpcBody::Query(query) => {
+                let (method, arguments) = encode_query(query);
+
+                dict.insert("y".into(), BTypes::TextString("q".to_owned()));
+                dict.insert("q".into(), BTypes::TextString(method));
+                dict.insert("a".into(), BTypes::Dict(arguments));
+            }
+            KrpcBody::Response(response) => {
+                dict.insert("y".into(), BTypes::TextString("r".to_owned()));
+                dict.insert("r".into(), BTypes::Dict(encode_response(response)));
+            }
+            KrpcBody::Error { code, message } => {
+                dict.insert("y".into(), BTypes::TextString("e".to_owned()));
+                dict.insert(
+                    "e".into(),
+                    BTypes::List(vec![
+                        BTypes::Integer(*code),
+                        BTypes::TextString(message.clone()),
+                    ]),
+                );
+            }
+        }
+
+        BTypes::Dict(dict).bencode()
+    }
+
+    pub fn decode(datagram: &[u8]) -> Result<Self, DhtError> {
+        let message = BTypes::bdecode(&datagram.to_vec())?;
+
+        let (transaction, message) = message.keyed_bytes("t")?;
+        let (kind, message) = message.keyed_bytes("y")?;
+
+        let body = match kind.as_slice() {
+            b"q" => {
+                let (method, message) = message.keyed_bytes("q")?;
+                let (arguments, _) = message.keyed_dict("a")?;
+
+                KrpcBody::Query(decode_query(&method, BTypes::Dict(arguments))?)
+            }
+            b"r" => {
+                let (response, _) = message.keyed_dict("r")?;
+
+                KrpcBody::Response(decode_response(BTypes::Dict(response))?)
+            }
+            b"e" => {
+                let (error, _) = message.keyed_list("e")?;
+                let mut error = error.into_iter();
+
+                let code = error.next().and_then(|c| c.expect_int().ok());
+                let message = error.next().and_then(|m| m.expect_bytes().ok());
+
+                KrpcBody::Error {
+                    code: code.unwrap_or(ERROR_GENERIC),
+                    message: String::from_utf8_lossy(&message.unwrap_or_default()).into_owned(),
+                }
+            }
+            _ => return Err(DhtError::Malformed("Unknown message type")),
+        };
+
+        Ok(Self { transaction, body })
+    }
+}
+
+fn encode_query(query: &Query) -> (String, DictInner) {
+    let mut arguments: DictInner = BTreeMap::new();
+
+    arguments.insert("id".into(), BTypes::ByteString(query.id().0.to_vec()));
+
+    let method = match query {
+        Query::Ping { .. } => "ping",
+        Query::FindNode { target, .. } => {
+            arguments.insert("target".into(), BTypes::ByteString(target.0.to_vec()));
+            "find_node"
+        }
+        Query::GetPeers { info_hash, .. } => {
+            arguments.insert("info_hash".into(), BTypes::ByteString(info_hash.0.to_vec()));
+            "get_peers"
+        }
+        Query::AnnouncePeer {
+            info_hash,
+            port,
+            implied_port,
+            token,
+            ..
+        } => {
+            arguments.insert("info_hash".into(), BTypes::ByteString(info_hash.0.to_vec()));
+            arguments.insert("port".into(), BTypes::Integer(*port as isize));
+            arguments.insert(
+                "implied_port".into(),
+                BTypes::Integer(*implied_port as isize),
+            );
+            arguments.insert("token".into(), BTypes::ByteString(token.clone()));
+            "announce_peer"
+        }
+        Query::Unknown { method, .. } => method,
+    };
+
+    (method.to_owned(), arguments)
+}
+
+fn decode_query(method: &[u8], arguments: BTypes) -> Result<Query, DhtError> {
+    let (id, arguments) = arguments.keyed_bytes("id")?;
+    let id = NodeId::from_bytes(&id)?;
+
+    let query = match method {
+        b"ping" => Query::Ping { id },
+        b"find_node" => {
+            let (target, _) = arguments.keyed_bytes("target")?;
+
+            Query::FindNode {
+                id,
+                target: NodeId::from_bytes(&target)?,
+            }
+        }
+        b"get_peers" => {
+            let (info_hash, _) = arguments.keyed_bytes("info_hash")?;
+
+            Query::GetPeers {
+                id,
+                info_hash: NodeId::from_bytes(&info_hash)?,
+            }
+        }
+        b"announce_peer" => {
+            let (info_hash, arguments) = arguments.keyed_bytes("info_hash")?;
+            let (port, arguments) = arguments.keyed_int("port")?;
+            let (token, arguments) = arguments.keyed_bytes("token")?;
+            let (implied_port, _) = arguments.keyed_optional("implied_port", BTypes::keyed_int)?;
+
+            let Ok(port) = u16::try_from(port) else {
+                return Err(DhtError::Malformed("Port out of range"));
+            };
+
+            Query::AnnouncePeer {
+                id,
+                info_hash: NodeId::from_bytes(&info_hash)?,
+                port,
+                implied_port: implied_port.unwrap_or(0) != 0,
+                token,
+            }
+        }
+        _ => Query::Unknown {
+            id,
+            method: String::from_utf8_lossy(method).into_owned(),
+        },
+    };
+
+    Ok(query)
+}
+
+fn encode_response(response: &Response) -> DictInner {
+    let mut dict: DictInner = BTreeMap::new();
+
+    dict.insert("id".into(), BTypes::ByteString(response.id.0.to_vec()));
+
+    let nodes = encode_nodes(&response.nodes, false);
+    let nodes6 = encode_nodes(&response.nodes, true);
+
+    if !nodes.is_empty() {
+        dict.insert("nodes".into(), BTypes::ByteString(nodes));
+    }
+
+    if !nodes6.is_empty() {
+        dict.insert("nodes6".into(), BTypes::ByteString(nodes6));
+    }
+
+    if !response.values.is_empty() {
+        let values = response
+            .values
+            .iter()
+            .map(|v| BTypes::ByteString(encode_compact(v)))
+            .collect();
+
+        dict.insert("values".into(), BTypes::List(values));
+    }
+
+    if let Some(token) = &response.token {
+        dict.insert("token".into(), BTypes::ByteString(token.clone()));
+    }
+
+    dict
+}
+
+fn decode_response(response: BTypes) -> Result<Response, DhtError> {
+    let (id, response) = response.keyed_bytes("id")?;
+    let (nodes, response) = response.keyed_optional("nodes", BTypes::keyed_bytes)?;
+    let (nodes6, response) = response.keyed_optional("nodes6", BTypes::keyed_bytes)?;
+    let (values, response) = response.keyed_optional("values", BTypes::keyed_list)?;
+    let (token, _) = response.keyed_optional("token", BTypes::keyed_bytes)?;
+
+    let mut decoded = Response::new(NodeId::from_bytes(&id)?);
+
+    for (compact, ipv6) in [(nodes, false), (nodes6, true)] {
+        if let Some(compact) = compact {
+            let Some(nodes) = decode_nodes(&compact, ipv6) else {
+                return Err(DhtError::Malformed("Bad compact node list"));
+            };
+
+            decoded.nodes.extend(nodes);
+        }
+    }
+
+    // Each value is a single compact peer, IPv4 and IPv6 may be mixed
+    for value in values.unwrap_or_default() {
+        let value = value.expect_bytes()?;
+
+        if let Some(peers) = decode_compact(&value, value.len()) {
+            decoded.values.extend(peers);
+        }
+    }
+
+    decoded.token = token;
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(s: &[u8; 20]) -> NodeId {
+        NodeId(*s)
+    }
+
+    #[test]
+    fn krpc_decode_ping() {
+        const SAMPLE: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+
+        let message = KrpcMessage::decode(SAMPLE).unwrap();
+
+        assert_eq!(
+            message,
+            KrpcMessage {
+                transaction: b"aa".to_vec(),
+                body: KrpcBody::Query(Query::Ping {
+                    id: id(b"abcdefghij0123456789")
+                }),
+            }
+        );
+        assert_eq!(message.encode(), SAMPLE);
+    }
+
+    #[test]
+    fn krpc_decode_get_peers_response() {
+        const SAMPLE: &[u8] = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+
+        let message = KrpcMessage::decode(SAMPLE).unwrap();
+
+        let KrpcBody::Response(response) = message.body else {
+            panic!("not a response");
+        };
+
+        assert_eq!(response.id, id(b"abcdefghij0123456789"));
+        assert_eq!(response.token, Some(b"aoeusnth".to_vec()));
+        assert_eq!(
+            response.values,
+            vec![
+                "97.120.106.101:11893".parse().unwrap(),
+                "105.100.104.116:28269".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn krpc_decode_error() {
+        const SAMPLE: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+        assert_eq!(
+            KrpcMessage::decode(SAMPLE).unwrap().body,
+            KrpcBody::Error {
+                code: ERROR_GENERIC,
+                message: "A Generic Error Ocurred".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn krpc_round_trip() {
+        let nodes = vec![
+            NodeInfo {
+                id: NodeId([1; 20]),
+                addr: "10.0.0.1:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: NodeId([2; 20]),
+                addr: "[2001:db8::1]:6881".parse().unwrap(),
+            },
+        ];
+
+        let bodies = [
+            KrpcBody::Query(Query::FindNode {
+                id: NodeId([3; 20]),
+                target: NodeId([4; 20]),
+            }),
+            KrpcBody::Query(Query::AnnouncePeer {
+                id: NodeId([3; 20]),
+                info_hash: NodeId([5; 20]),
+                port: 6881,
+                implied_port: true,
+                token: b"token".to_vec(),
+            }),
+            KrpcBody::Query(Query::Unknown {
+                id: NodeId([3; 20]),
+                method: "vote".to_owned(),
+            }),
+            KrpcBody::Response(Response {
+                id: NodeId([6; 20]),
+                nodes,
+                values: vec!["10.0.0.2:1".parse().unwrap(), "[::1]:2".parse().unwrap()],
+                token: Some(vec![0, 1, 2]),
+            }),
+        ];
+
+        for body in bodies {
+            let message = KrpcMessage {
+                transaction: vec![0, 7],
+                body,
+            };
+
+            assert_eq!(KrpcMessage::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn krpc_decode_malformed() {
+        assert!(matches!(
+            KrpcMessage::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe"),
+            Err(DhtError::Malformed(_))
+        ));
+        assert!(matches!(
+            KrpcMessage::decode(b"d1:t2:aa1:y1:xe"),
+            Err(DhtError::Malformed(_))
+        ));
+        assert!(matches!(
+            KrpcMessage::decode(b"d1:y1:qe"),
+            Err(DhtError::Decode(_))
+        ));
+    }
+}
@@ -0,0 +1,182 @@
+use super::krpc::{NodeId, NodeInfo};
+use std::net::SocketAddr;
+
+/// Nodes per bucket.
+pub const K: usize = 8;
+
+/// Unanswered queries after which a node may be replaced.
+const MAX_FAILURES: u8 = 2;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    failures: u8,
+}
+
+/// Kademlia routing table, bucket `i` holds the nodes whose distance to us has `i` leading zero bits.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = self.own.distance(id);
+        let leading = distance
+            .iter()
+            .position(|b| *b != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+
+        Some(leading)
+    }
+
+    /// Adds or refreshes a node that answered us or queried us.
+    /// Returns false if its bucket is full of good nodes.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return false;
+        };
+
+        let bucket = &mut self.buckets[index];
+
+        // Most recently seen nodes live at the end
+        if let Some(position) = bucket.iter().position(|e| e.node.id == node.id) {
+            bucket.remove(position);
+        } else if bucket.len() >= K {
+            let Some(bad) = bucket.iter().position(|e| e.failures >= MAX_FAILURES) else {
+                return false;
+            };
+
+            bucket.remove(bad);
+        }
+
+        bucket.push(Entry { node, failures: 0 });
+
+        true
+    }
+
+    /// Records an unanswered query, bad nodes are evicted once a better one shows up.
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == *addr {
+                entry.failures = entry.failures.saturating_add(1);
+            }
+        }
+    }
+
+    /// Up to `count` good nodes, closest to `target` first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|e| e.failures < MAX_FAILURES)
+            .map(|e| e.node)
+            .collect::<Vec<_>>();
+
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.truncate(count);
+
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets.iter().flatten().map(|e| e.node).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(first: u8, last: u8) -> NodeInfo {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+
+        NodeInfo {
+            id: NodeId(id),
+            addr: format!("10.0.{first}.{last}:6881").parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn routing_bucket_full() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+
+        // Same top bit, so all land in bucket 0
+        for i in 0..K as u8 {
+            assert!(table.insert(node(0x80, i)));
+        }
+        assert!(!table.insert(node(0x80, 100)));
+
+        // Refreshing a known node is always fine
+        assert!(table.insert(node(0x80, 0)));
+
+        // Other buckets are unaffected
+        assert!(table.insert(node(0x01, 0)));
+
+        // Own id is never stored
+        assert!(!table.insert(NodeInfo {
+            id: NodeId([0; 20]),
+            addr: "10.0.0.1:1".parse().unwrap()
+        }));
+
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn routing_replace_bad() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+
+        for i in 0..K as u8 {
+            table.insert(node(0x80, i));
+        }
+
+        let bad = node(0x80, 3);
+        table.failed(&bad.addr);
+        assert!(!table.insert(node(0x80, 100)));
+
+        table.failed(&bad.addr);
+        assert!(table.insert(node(0x80, 100)));
+
+        assert_eq!(table.len(), K);
+        assert!(!table.nodes().contains(&bad));
+    }
+
+    #[test]
+    fn routing_closest() {
+        let mut table = RoutingTable::new(NodeId([0; 20]));
+
+        for (first, last) in [(0x80, 1), (0x40, 1), (0x41, 0), (0x01, 7), (0xff, 0)] {
+            table.insert(node(first, last));
+        }
+
+        let mut target = [0; 20];
+        target[0] = 0x41;
+
+        let closest = table.closest(&NodeId(target), 3);
+
+        assert_eq!(closest, vec![node(0x41, 0), node(0x40, 1), node(0x01, 7)]);
+    }
+}
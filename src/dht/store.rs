@@ -0,0 +1,151 @@
+use super::krpc::NodeId;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The token secret changes this often, tokens stay valid for one to two rotations.
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Announced peers are forgotten after this long without a fresh announce.
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Peers returned in a single `get_peers` response.
+pub const MAX_VALUES: usize = 50;
+
+/// Hands out and checks the write tokens of `get_peers` and `announce_peer`.
+/// A token is the SHA-1 of the querying IP and a secret, so nothing is stored per node.
+#[derive(Debug)]
+pub struct TokenSecret {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl Default for TokenSecret {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenSecret {
+    pub fn new() -> Self {
+        Self {
+            current: rand::random(),
+            previous: rand::random(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self) {
+        while self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated += TOKEN_ROTATION;
+        }
+    }
+
+    pub fn token(&mut self, ip: IpAddr) -> Vec<u8> {
+        self.rotate();
+
+        token_for(&self.current, ip)
+    }
+
+    /// Tokens made with the current or the previous secret are accepted.
+    pub fn verify(&mut self, ip: IpAddr, token: &[u8]) -> bool {
+        self.rotate();
+
+        token == token_for(&self.current, ip) || token == token_for(&self.previous, ip)
+    }
+}
+
+fn token_for(secret: &[u8; 16], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.update(secret);
+
+    hasher.finalize().to_vec()
+}
+
+/// Peers announced to us, per info hash.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    torrents: HashMap<NodeId, HashMap<SocketAddr, Instant>>,
+}
+
+impl PeerStore {
+    pub fn insert(&mut self, info_hash: NodeId, peer: SocketAddr) {
+        self.torrents
+            .entry(info_hash)
+            .or_default()
+            .insert(peer, Instant::now());
+    }
+
+    /// Up to `MAX_VALUES` peers that announced within `PEER_TTL`.
+    pub fn get(&mut self, info_hash: &NodeId) -> Vec<SocketAddr> {
+        let Some(peers) = self.torrents.get_mut(info_hash) else {
+            return Vec::new();
+        };
+
+        peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+
+        if peers.is_empty() {
+            self.torrents.remove(info_hash);
+            return Vec::new();
+        }
+
+        peers.keys().take(MAX_VALUES).copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn token_rotation() {
+        let mut secret = TokenSecret::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let token = secret.token(ip);
+        assert!(secret.verify(ip, &token));
+        assert!(!secret.verify("10.0.0.2".parse().unwrap(), &token));
+
+        tokio::time::advance(TOKEN_ROTATION).await;
+        assert!(secret.verify(ip, &token));
+        assert_ne!(secret.token(ip), token);
+
+        tokio::time::advance(TOKEN_ROTATION).await;
+        assert!(!secret.verify(ip, &token));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_store_expiry() {
+        let mut store = PeerStore::default();
+        let info_hash = NodeId([1; 20]);
+        let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+
+        store.insert(info_hash, first);
+        tokio::time::advance(PEER_TTL / 2).await;
+        store.insert(info_hash, second);
+
+        let mut peers = store.get(&info_hash);
+        peers.sort();
+        assert_eq!(peers, vec![first, second]);
+        assert!(store.get(&NodeId([2; 20])).is_empty());
+
+        tokio::time::advance(PEER_TTL / 2).await;
+        assert_eq!(store.get(&info_hash), vec![second]);
+
+        for i in 0..100 {
+            store.insert(info_hash, format!("10.1.0.{i}:1").parse().unwrap());
+        }
+        assert_eq!(store.get(&info_hash).len(), MAX_VALUES);
+    }
+}
@@ -0,0 +1,185 @@
+pub mod choker;
+pub mod picker;
+pub mod pipeline;
+
+use crate::network::ProtocolError;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// One bit per piece, most significant bit of the first byte is piece 0, as sent in `bitfield` messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// All pieces missing.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Every piece present, e.g. for a seed.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        (0..len).for_each(|i| bitfield.set(i));
+
+        bitfield
+    }
+
+    /// Takes the payload of a `bitfield` message, which must have exactly enough bytes and no spare bits set.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, ProtocolError> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(ProtocolError::BadBitfield);
+        }
+
+        let spare = bytes.len() * 8 - len;
+
+        if spare > 0 && bytes[bytes.len() - 1] & ((1 << spare) - 1) != 0 {
+            return Err(ProtocolError::BadBitfield);
+        }
+
+        Ok(Self {
+            bytes: bytes.to_vec(),
+            len,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Panics past the last piece.
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.len, "piece {index} out of range");
+
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    /// Number of pieces present.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indices of the pieces present.
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| self.get(*i))
+    }
+}
+
+/// Transfer rate over a sliding window.
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    window: Duration,
+    started: Instant,
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl RateMeter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            started: Instant::now(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= self.window)
+        {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back((now, bytes));
+    }
+
+    /// Bytes per second, a meter younger than its window averages over its age, but at least a second.
+    pub fn rate(&self) -> f64 {
+        let now = Instant::now();
+
+        let bytes = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) < self.window)
+            .map(|(_, bytes)| bytes)
+            .sum::<usize>();
+
+        let span = self
+            .started
+            .elapsed()
+            .clamp(Duration::from_secs(1), self.window);
+
+        bytes as f64 / span.as_secs_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfield_bytes() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0);
+        bitfield.set(9);
+
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert_eq!(
+            Bitfield::from_bytes(bitfield.as_bytes(), 10).unwrap(),
+            bitfield
+        );
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), vec![0, 9]);
+        assert!(!bitfield.get(10));
+
+        assert_eq!(Bitfield::full(10).as_bytes(), &[0xff, 0b1100_0000]);
+        assert!(Bitfield::full(10).is_complete());
+
+        // Wrong length, spare bit set
+        assert!(Bitfield::from_bytes(&[0xff], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff, 0b1110_0000], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xff], 8).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_meter_window() {
+        let mut meter = RateMeter::new(Duration::from_secs(10));
+
+        meter.record(1000);
+        assert_eq!(meter.rate(), 1000.0);
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        meter.record(1000);
+        assert_eq!(meter.rate(), 500.0);
+
+        tokio::time::advance(Duration::from_secs(8)).await;
+        assert_eq!(meter.rate(), 100.0);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(meter.rate(), 0.0);
+    }
+}
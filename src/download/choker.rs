@@ -0,0 +1,376 @@
+use super::RateMeter;
+use rand::seq::IndexedRandom;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Window of the rates peers are ranked by.
+const RATE_WINDOW: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokerConfig {
+    /// Peers unchoked at once, one of them the optimistic unchoke.
+    pub upload_slots: usize,
+
+    /// How often `Choker::rechoke` is meant to run.
+    pub rechoke_interval: Duration,
+
+    /// The optimistic unchoke moves to another peer this often.
+    pub optimistic_interval: Duration,
+
+    /// Peer that sent us no block for this long while we had requests outstanding to it is snubbed while we download,
+    /// it loses its regular slot and can only get the optimistic one.
+    pub snub_timeout: Duration,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            upload_slots: 4,
+            rechoke_interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+            snub_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChokerPeer {
+    /// Whether the peer is interested in our pieces.
+    interested: bool,
+    choked: bool,
+    download: RateMeter,
+    upload: RateMeter,
+
+    /// Last block received from the peer, or when we started requesting from it.
+    /// `None` while we have no requests outstanding to it, a peer we ask nothing of cannot snub us.
+    last_block: Option<Instant>,
+}
+
+/// Tit-for-tat choking: peers that give us the most get unchoked, plus one optimistic unchoke
+/// so newcomers get a chance to prove themselves.
+/// Ranks by download rate while we download, and by upload rate once we seed.
+#[derive(Debug)]
+pub struct Choker {
+    config: ChokerConfig,
+    peers: HashMap<SocketAddr, ChokerPeer>,
+    optimistic: Option<SocketAddr>,
+    optimistic_since: Instant,
+    seeding: bool,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+            optimistic: None,
+            optimistic_since: Instant::now(),
+            seeding: false,
+        }
+    }
+
+    pub fn config(&self) -> &ChokerConfig {
+        &self.config
+    }
+
+    /// New peers start choked and not interested.
+    pub fn add_peer(&mut self, peer: SocketAddr) {
+        self.peers.insert(
+            peer,
+            ChokerPeer {
+                interested: false,
+                choked: true,
+                download: RateMeter::new(RATE_WINDOW),
+                upload: RateMeter::new(RATE_WINDOW),
+                last_block: None,
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+    }
+
+    /// Records the peer's `interested` and `not interested` messages.
+    pub fn set_interested(&mut self, peer: &SocketAddr, interested: bool) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.interested = interested;
+        }
+    }
+
+    /// Switches ranking to upload rates once the download is complete.
+    pub fn set_seeding(&mut self, seeding: bool) {
+        self.seeding = seeding;
+    }
+
+    pub fn record_download(&mut self, peer: &SocketAddr, bytes: usize) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.download.record(bytes);
+
+            if state.last_block.is_some() {
+                state.last_block = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Whether we have requests outstanding to the peer, the snub timeout starts when they do.
+    pub fn set_requesting(&mut self, peer: &SocketAddr, requesting: bool) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.last_block = match state.last_block {
+                Some(_) if requesting => state.last_block,
+                _ if requesting => Some(Instant::now()),
+                _ => None,
+            };
+        }
+    }
+
+    pub fn record_upload(&mut self, peer: &SocketAddr, bytes: usize) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.upload.record(bytes);
+        }
+    }
+
+    /// Whether we choke the peer, unknown peers are choked.
+    pub fn is_choked(&self, peer: &SocketAddr) -> bool {
+        self.peers.get(peer).is_none_or(|s| s.choked)
+    }
+
+    pub fn is_snubbed(&self, peer: &SocketAddr) -> bool {
+        !self.seeding
+            && self
+                .peers
+                .get(peer)
+                .and_then(|s| s.last_block)
+                .is_some_and(|t| t.elapsed() >= self.config.snub_timeout)
+    }
+
+    /// Recomputes who is unchoked, to be called every `rechoke_interval`.
+    /// Returns the peers whose state changed along with whether they are now choked.
+    pub fn rechoke(&mut self) -> Vec<(SocketAddr, bool)> {
+        let rate = |s: &ChokerPeer| {
+            if self.seeding {
+                s.upload.rate()
+            } else {
+                s.download.rate()
+            }
+        };
+
+        let mut ranked = self
+            .peers
+            .iter()
+            .filter(|(addr, s)| s.interested && !self.is_snubbed(addr))
+            .map(|(addr, s)| (*addr, rate(s)))
+            .collect::<Vec<_>>();
+
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let regular = ranked
+            .into_iter()
+            .take(self.config.upload_slots.saturating_sub(1))
+            .map(|(addr, _)| addr)
+            .collect::<BTreeSet<_>>();
+
+        self.rotate_optimistic(&regular);
+
+        let mut changes = Vec::new();
+
+        for (addr, state) in &mut self.peers {
+            let choked = !regular.contains(addr) && self.optimistic != Some(*addr);
+
+            if state.choked != choked {
+                state.choked = choked;
+                changes.push((*addr, choked));
+            }
+        }
+
+        changes
+    }
+
+    /// Keeps the optimistic unchoke for `optimistic_interval`, unless it left, lost interest or earned a regular slot.
+    fn rotate_optimistic(&mut self, regular: &BTreeSet<SocketAddr>) {
+        let eligible = |addr: &SocketAddr, s: &ChokerPeer| s.interested && !regular.contains(addr);
+
+        let current = self
+            .optimistic
+            .and_then(|addr| self.peers.get(&addr).map(|s| eligible(&addr, s)))
+            .unwrap_or(false);
+
+        if current && self.optimistic_since.elapsed() < self.config.optimistic_interval {
+            return;
+        }
+
+        let candidates = self
+            .peers
+            .iter()
+            .filter(|(addr, s)| eligible(addr, s) && Some(**addr) != self.optimistic)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        // With nobody else to pick, a still eligible optimistic unchoke stays
+        self.optimistic = match candidates.choose(&mut rand::rng()) {
+            Some(addr) => Some(*addr),
+            None if current => self.optimistic,
+            None => None,
+        };
+        self.optimistic_since = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn unchoked(choker: &Choker, peers: &[SocketAddr]) -> Vec<SocketAddr> {
+        peers
+            .iter()
+            .filter(|p| !choker.is_choked(p))
+            .copied()
+            .collect()
+    }
+
+    fn choker_with_peers(count: u16) -> (Choker, Vec<SocketAddr>) {
+        let mut choker = Choker::new(ChokerConfig::default());
+        let peers = (1..=count).map(peer).collect::<Vec<_>>();
+
+        for p in &peers {
+            choker.add_peer(*p);
+            choker.set_interested(p, true);
+        }
+
+        transfer(&mut choker, &peers);
+
+        (choker, peers)
+    }
+
+    /// Later peers send us more, earlier peers get more from us.
+    fn transfer(choker: &mut Choker, peers: &[SocketAddr]) {
+        for (i, p) in peers.iter().enumerate() {
+            choker.record_download(p, 1000 * (i + 1));
+            choker.record_upload(p, 1000 * (peers.len() - i));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn choker_top_rates() {
+        let (mut choker, peers) = choker_with_peers(6);
+
+        let changes = choker.rechoke();
+        assert_eq!(changes.len(), 4);
+        assert!(changes.iter().all(|(_, choked)| !choked));
+
+        // Three fastest uploaders to us, plus one optimistic among the rest
+        let open = unchoked(&choker, &peers);
+        assert!(open.ends_with(&peers[3..]));
+        assert_eq!(open.len(), 4);
+
+        // Nothing changes until the optimistic interval is up
+        assert!(choker.rechoke().is_empty());
+
+        // Seeding ranks by what we upload to them
+        choker.set_seeding(true);
+        choker.rechoke();
+        assert!(unchoked(&choker, &peers).starts_with(&peers[..3]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn choker_optimistic_rotation() {
+        let (mut choker, peers) = choker_with_peers(6);
+        let regular = &peers[3..];
+
+        choker.rechoke();
+        let optimistic = choker.optimistic.unwrap();
+        assert!(!regular.contains(&optimistic));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        transfer(&mut choker, &peers);
+        choker.rechoke();
+        assert_eq!(choker.optimistic, Some(optimistic));
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        transfer(&mut choker, &peers);
+        let changes = choker.rechoke();
+        assert_ne!(choker.optimistic, Some(optimistic));
+        assert!(changes.contains(&(optimistic, true)));
+
+        // Losing interest loses the slot right away
+        let optimistic = choker.optimistic.unwrap();
+        choker.set_interested(&optimistic, false);
+        choker.rechoke();
+        assert!(choker.is_choked(&optimistic));
+        assert!(choker.optimistic.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn choker_snubbed() {
+        let mut choker = Choker::new(ChokerConfig {
+            upload_slots: 2,
+            optimistic_interval: Duration::from_secs(120),
+            ..ChokerConfig::default()
+        });
+
+        let (fast, slow, other) = (peer(1), peer(2), peer(3));
+
+        for p in [fast, slow, other] {
+            choker.add_peer(p);
+            choker.set_requesting(&p, true);
+        }
+
+        choker.set_interested(&fast, true);
+        choker.set_interested(&slow, true);
+        choker.record_download(&fast, 100_000);
+        choker.rechoke();
+        assert!(!choker.is_choked(&fast));
+        assert_eq!(choker.optimistic, Some(slow));
+
+        // The fast peer stops sending, the others keep trickling in
+        tokio::time::advance(Duration::from_secs(60)).await;
+        choker.set_interested(&other, true);
+        choker.record_download(&slow, 10);
+        choker.record_download(&other, 1000);
+
+        assert!(choker.is_snubbed(&fast));
+        assert!(!choker.is_snubbed(&slow));
+
+        // Other takes the regular slot, slow keeps the optimistic one
+        choker.rechoke();
+        assert!(choker.is_choked(&fast));
+        assert!(!choker.is_choked(&other));
+        assert_eq!(choker.optimistic, Some(slow));
+
+        // Nothing requested means nothing to snub, the timeout restarts with the next request
+        choker.set_requesting(&fast, false);
+        assert!(!choker.is_snubbed(&fast));
+        choker.set_requesting(&fast, true);
+        assert!(!choker.is_snubbed(&fast));
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(choker.is_snubbed(&fast));
+
+        // Snubbing only matters while downloading
+        choker.set_seeding(true);
+        assert!(!choker.is_snubbed(&fast));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn choker_uninterested() {
+        let mut choker = Choker::new(ChokerConfig::default());
+        let p = peer(1);
+
+        choker.add_peer(p);
+        choker.record_download(&p, 1000);
+        assert!(choker.rechoke().is_empty());
+        assert!(choker.is_choked(&p));
+
+        choker.set_interested(&p, true);
+        assert_eq!(choker.rechoke(), vec![(p, false)]);
+
+        choker.remove_peer(&p);
+        assert!(choker.is_choked(&p));
+        assert!(choker.rechoke().is_empty());
+    }
+}
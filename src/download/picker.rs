@@ -0,0 +1,335 @@
+use super::Bitfield;
+use crate::metainfo::MetaInfo;
+use crate::network::ProtocolError;
+use rand::seq::IndexedRandom;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+
+/// Pieces picked at random before switching to rarest first, so there is something to upload early on.
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Download priority of a piece. A piece is only picked when no piece of a higher priority is available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// Never picked, e.g. the piece only covers unwanted files.
+    Skip,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Chooses which piece to download next from a peer.
+/// Tracks how many connected peers have each piece, from their `bitfield` and `have` messages,
+/// and prefers the rarest piece within the highest priority available.
+#[derive(Debug)]
+pub struct PiecePicker {
+    have: Bitfield,
+
+    /// Number of connected peers having each piece.
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+
+    /// Pieces handed out by `pick` and not yet completed or aborted.
+    in_progress: BTreeSet<usize>,
+    peers: HashMap<SocketAddr, Bitfield>,
+    random_first: usize,
+}
+
+impl PiecePicker {
+    pub fn new(info: &MetaInfo) -> Self {
+        let pieces = info.piece_count();
+
+        Self {
+            have: Bitfield::new(pieces),
+            availability: vec![0; pieces],
+            priorities: vec![Priority::Normal; pieces],
+            in_progress: BTreeSet::new(),
+            peers: HashMap::new(),
+            random_first: RANDOM_FIRST_PIECES,
+        }
+    }
+
+    /// Number of completed pieces below which picks are random instead of rarest first.
+    pub fn set_random_first(&mut self, pieces: usize) {
+        self.random_first = pieces;
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.availability.len()
+    }
+
+    /// Pieces we have, for our own `bitfield` message.
+    pub fn have_bitfield(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn set_priority(&mut self, index: usize, priority: Priority) {
+        if let Some(p) = self.priorities.get_mut(index) {
+            *p = priority;
+        }
+    }
+
+    /// Records a peer's `bitfield` message, replacing whatever we knew about the peer.
+    pub fn bitfield(&mut self, peer: SocketAddr, bytes: &[u8]) -> Result<(), ProtocolError> {
+        let bitfield = Bitfield::from_bytes(bytes, self.piece_count())?;
+
+        self.remove_peer(&peer);
+
+        for index in bitfield.ones() {
+            self.availability[index] += 1;
+        }
+
+        self.peers.insert(peer, bitfield);
+
+        Ok(())
+    }
+
+    /// Records a peer's `have` message, peers without pieces may skip the bitfield and only send these.
+    pub fn have(&mut self, peer: SocketAddr, index: u32) -> Result<(), ProtocolError> {
+        let pieces = self.piece_count();
+
+        if index as usize >= pieces {
+            return Err(ProtocolError::PieceOutOfRange(index));
+        }
+
+        let bitfield = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| Bitfield::new(pieces));
+
+        if !bitfield.get(index as usize) {
+            bitfield.set(index as usize);
+            self.availability[index as usize] += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Forgets a disconnected peer's pieces.
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        if let Some(bitfield) = self.peers.remove(peer) {
+            for index in bitfield.ones() {
+                self.availability[index] -= 1;
+            }
+        }
+    }
+
+    pub fn peer_has(&self, peer: &SocketAddr, index: usize) -> bool {
+        self.peers.get(peer).is_some_and(|b| b.get(index))
+    }
+
+    fn wanted(&self, index: usize) -> bool {
+        !self.have.get(index)
+            && self.priorities[index] != Priority::Skip
+            && !self.in_progress.contains(&index)
+    }
+
+    /// Whether every piece we still want is in progress, i.e. `pick` has nothing left to hand out.
+    pub fn all_picked(&self) -> bool {
+        (0..self.piece_count()).all(|i| !self.wanted(i))
+    }
+
+    /// Whether the peer has a piece we still want, i.e. whether we should be interested.
+    pub fn is_interesting(&self, peer: &SocketAddr) -> bool {
+        self.peers.get(peer).is_some_and(|bitfield| {
+            bitfield
+                .ones()
+                .any(|i| !self.have.get(i) && self.priorities[i] != Priority::Skip)
+        })
+    }
+
+    /// Picks a piece the peer has and nobody is downloading, and marks it in progress.
+    /// Ties in rarity are broken at random so peers of the swarm spread out.
+    pub fn pick(&mut self, peer: &SocketAddr) -> Option<usize> {
+        let bitfield = self.peers.get(peer)?;
+
+        let candidates = bitfield
+            .ones()
+            .filter(|i| self.wanted(*i))
+            .collect::<Vec<_>>();
+
+        let top = candidates.iter().map(|i| self.priorities[*i]).max()?;
+        let candidates = candidates
+            .into_iter()
+            .filter(|i| self.priorities[*i] == top)
+            .collect::<Vec<_>>();
+
+        let candidates = if self.have.count() < self.random_first {
+            candidates
+        } else {
+            let rarest = candidates.iter().map(|i| self.availability[*i]).min()?;
+
+            candidates
+                .into_iter()
+                .filter(|i| self.availability[*i] == rarest)
+                .collect()
+        };
+
+        let picked = *candidates.choose(&mut rand::rng())?;
+        self.in_progress.insert(picked);
+
+        Some(picked)
+    }
+
+    /// Returns an unfinished piece to the pool, e.g. its peer disconnected or it failed the hash check.
+    pub fn abort(&mut self, index: usize) {
+        self.in_progress.remove(&index);
+    }
+
+    /// Records a downloaded and verified piece.
+    pub fn complete(&mut self, index: usize) {
+        self.in_progress.remove(&index);
+        self.have.set(index);
+    }
+
+    /// Whether every piece not skipped has been downloaded.
+    pub fn is_finished(&self) -> bool {
+        (0..self.piece_count()).all(|i| self.have.get(i) || self.priorities[i] == Priority::Skip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::DownloadTypes;
+    use std::collections::BTreeMap;
+
+    fn picker(pieces: usize) -> PiecePicker {
+        let info = MetaInfo {
+            name: "test".to_string(),
+            piece_length: 16,
+            pieces: vec![0; pieces * 20],
+            files: DownloadTypes::Single {
+                length: pieces * 16,
+            },
+            leftovers: BTreeMap::new(),
+        };
+
+        PiecePicker::new(&info)
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn picker_availability() {
+        let mut picker = picker(10);
+
+        picker.bitfield(peer(1), &[0b1100_0000, 0]).unwrap();
+        picker
+            .bitfield(peer(2), &[0b0100_0000, 0b0100_0000])
+            .unwrap();
+        picker.have(peer(3), 1).unwrap();
+        picker.have(peer(3), 1).unwrap();
+
+        assert_eq!(picker.availability(0), 1);
+        assert_eq!(picker.availability(1), 3);
+        assert_eq!(picker.availability(9), 1);
+
+        // A new bitfield replaces the old one
+        picker.bitfield(peer(1), &[0, 0]).unwrap();
+        assert_eq!(picker.availability(0), 0);
+
+        picker.remove_peer(&peer(3));
+        assert_eq!(picker.availability(1), 1);
+
+        assert!(matches!(
+            picker.bitfield(peer(4), &[0]),
+            Err(ProtocolError::BadBitfield)
+        ));
+        assert!(matches!(
+            picker.have(peer(4), 10),
+            Err(ProtocolError::PieceOutOfRange(10))
+        ));
+    }
+
+    #[test]
+    fn picker_rarest_first() {
+        let mut picker = picker(8);
+        picker.set_random_first(0);
+
+        picker.bitfield(peer(1), &[0b1111_0000]).unwrap();
+        picker.bitfield(peer(2), &[0b1101_0000]).unwrap();
+        picker.bitfield(peer(3), &[0b1001_1000]).unwrap();
+
+        // Piece 2 and then 1 are rarest among what peer 1 has
+        assert_eq!(picker.pick(&peer(1)), Some(2));
+        assert_eq!(picker.pick(&peer(1)), Some(1));
+
+        // Aborted pieces can be picked again
+        picker.abort(2);
+        assert_eq!(picker.pick(&peer(1)), Some(2));
+
+        picker.complete(1);
+        picker.complete(2);
+        let mut tied = [picker.pick(&peer(1)), picker.pick(&peer(1))];
+        tied.sort();
+        assert_eq!(tied, [Some(0), Some(3)]);
+        assert_eq!(picker.pick(&peer(1)), None);
+        assert_eq!(picker.pick(&peer(2)), None);
+
+        // Peer 3 still has piece 4, nobody else has anything we want
+        picker.complete(0);
+        picker.complete(3);
+        assert!(picker.is_interesting(&peer(3)));
+        assert!(!picker.is_interesting(&peer(2)));
+        assert_eq!(picker.pick(&peer(9)), None);
+    }
+
+    #[test]
+    fn picker_priority() {
+        let mut picker = picker(4);
+        picker.set_random_first(0);
+
+        picker.bitfield(peer(1), &[0b1111_0000]).unwrap();
+        picker.bitfield(peer(2), &[0b1000_0000]).unwrap();
+        picker.bitfield(peer(3), &[0b1100_0000]).unwrap();
+
+        picker.set_priority(0, Priority::High);
+        picker.set_priority(2, Priority::Skip);
+        picker.set_priority(3, Priority::Skip);
+
+        // Highest priority wins over rarity, skipped pieces are never picked
+        assert_eq!(picker.pick(&peer(1)), Some(0));
+        assert_eq!(picker.pick(&peer(1)), Some(1));
+        assert_eq!(picker.pick(&peer(1)), None);
+
+        picker.complete(0);
+        picker.complete(1);
+        assert!(picker.is_finished());
+        assert!(!picker.is_interesting(&peer(1)));
+    }
+
+    #[test]
+    fn picker_random_first() {
+        let mut picker = picker(16);
+
+        picker.bitfield(peer(1), &[0xff, 0xff]).unwrap();
+        picker.bitfield(peer(2), &[0xff, 0xfe]).unwrap();
+
+        // Random picks while we have fewer than RANDOM_FIRST_PIECES, rarest first would always pick piece 15
+        let picks = (0..64)
+            .map(|_| {
+                let index = picker.pick(&peer(1)).unwrap();
+                picker.abort(index);
+                index
+            })
+            .collect::<BTreeSet<_>>();
+        assert!(picks.len() > 1);
+
+        for index in 0..RANDOM_FIRST_PIECES {
+            picker.complete(index);
+        }
+
+        // Then the rarest, piece 15 is only on peer 1
+        for _ in 0..8 {
+            assert_eq!(picker.pick(&peer(1)), Some(15));
+            picker.abort(15);
+        }
+    }
+}
@@ -0,0 +1,698 @@
+use super::RateMeter;
+use super::picker::PiecePicker;
+use crate::metainfo::MetaInfo;
+use crate::network::ProtocolError;
+use crate::network::message::PeerMessage;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Pieces are requested in blocks of this size, only the last block of a piece may be shorter.
+pub const BLOCK_LEN: usize = 16384;
+
+/// Window of the per peer download rate the queue depth is derived from.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// A slice of a piece, as in `request`, `piece` and `cancel` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Block {
+    /// Blocks of a piece of `size` bytes.
+    pub fn split(piece: u32, size: usize) -> impl Iterator<Item = Block> {
+        (0..size).step_by(BLOCK_LEN).map(move |begin| Block {
+            piece,
+            begin: begin as u32,
+            length: BLOCK_LEN.min(size - begin) as u32,
+        })
+    }
+
+    pub fn request(&self) -> PeerMessage {
+        PeerMessage::Request {
+            index: self.piece,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+
+    pub fn cancel(&self) -> PeerMessage {
+        PeerMessage::Cancel {
+            index: self.piece,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    /// Outstanding requests per peer, however slow it is.
+    pub min_queue_depth: usize,
+
+    /// Outstanding requests per peer, however fast it is, further capped by the `reqq` the peer advertises.
+    pub max_queue_depth: usize,
+
+    /// Requests queued to a peer cover this long at its current rate, so the link never idles.
+    pub queue_time: Duration,
+
+    /// Requests unanswered this long are cancelled and the block requested again.
+    pub request_timeout: Duration,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            min_queue_depth: 4,
+            max_queue_depth: 250,
+            queue_time: Duration::from_secs(3),
+            request_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Outstanding requests for a peer downloading at `rate` bytes per second.
+    pub fn queue_depth(&self, rate: f64, reqq: Option<usize>) -> usize {
+        let wanted = (rate * self.queue_time.as_secs_f64() / BLOCK_LEN as f64).ceil() as usize;
+        let max = reqq.map_or(self.max_queue_depth, |r| r.min(self.max_queue_depth));
+
+        wanted.max(self.min_queue_depth).min(max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested,
+    Received,
+}
+
+/// Piece being downloaded, blocks are assembled in memory until the hash can be checked.
+#[derive(Debug)]
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+}
+
+#[derive(Debug)]
+struct PeerPipeline {
+    in_flight: Vec<(Block, Instant)>,
+
+    /// Requests to withdraw because another peer delivered the block first.
+    cancels: Vec<Block>,
+    reqq: Option<usize>,
+    rate: RateMeter,
+    choked: bool,
+}
+
+/// What became of a received block.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// Block we did not ask for, or already have, it is dropped.
+    Unexpected,
+    Stored,
+    /// Last block of a piece arrived and the piece matches its hash.
+    /// It stays in progress with the picker until stored, then the caller completes or aborts it.
+    Completed {
+        piece: u32,
+        data: Vec<u8>,
+    },
+    /// Last block of a piece arrived but the piece is corrupt, it will be downloaded again.
+    HashFailed(u32),
+}
+
+/// Keeps a queue of outstanding block requests per peer, sized to the peer's throughput,
+/// and assembles the blocks into verified pieces.
+///
+/// Once every wanted piece has been picked and every block requested, the pipeline enters endgame mode:
+/// peers with spare queue space are also asked for blocks already requested from others,
+/// so the last blocks do not hinge on a single slow peer, and the losing requests are cancelled.
+#[derive(Debug)]
+pub struct Pipeline {
+    info: Arc<MetaInfo>,
+    picker: PiecePicker,
+    pieces: BTreeMap<u32, PartialPiece>,
+    peers: HashMap<SocketAddr, PeerPipeline>,
+    config: PipelineConfig,
+}
+
+impl Pipeline {
+    pub fn new(info: Arc<MetaInfo>, config: PipelineConfig) -> Self {
+        Self {
+            picker: PiecePicker::new(&info),
+            info,
+            pieces: BTreeMap::new(),
+            peers: HashMap::new(),
+            config,
+        }
+    }
+
+    pub fn picker(&self) -> &PiecePicker {
+        &self.picker
+    }
+
+    /// For the peers' `bitfield` and `have` messages and piece priorities.
+    pub fn picker_mut(&mut self) -> &mut PiecePicker {
+        &mut self.picker
+    }
+
+    /// Peers start out choking us.
+    pub fn add_peer(&mut self, peer: SocketAddr) {
+        self.peers.insert(
+            peer,
+            PeerPipeline {
+                in_flight: Vec::new(),
+                cancels: Vec::new(),
+                reqq: None,
+                rate: RateMeter::new(RATE_WINDOW),
+                choked: true,
+            },
+        );
+    }
+
+    /// Forgets a disconnected peer, its outstanding blocks go back to the pool.
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        if let Some(state) = self.peers.remove(peer) {
+            self.release(state.in_flight);
+        }
+
+        self.picker.remove_peer(peer);
+    }
+
+    /// Maximum outstanding requests the peer advertised in its extended handshake.
+    pub fn set_reqq(&mut self, peer: &SocketAddr, reqq: usize) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.reqq = Some(reqq);
+        }
+    }
+
+    /// A choking peer discards our requests, so they go back to the pool.
+    pub fn set_choked(&mut self, peer: &SocketAddr, choked: bool) {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return;
+        };
+
+        state.choked = choked;
+
+        if choked {
+            let in_flight = std::mem::take(&mut state.in_flight);
+            state.cancels.clear();
+            self.release(in_flight);
+        }
+    }
+
+    /// Download rate of a peer in bytes per second.
+    pub fn rate(&self, peer: &SocketAddr) -> f64 {
+        self.peers.get(peer).map_or(0.0, |s| s.rate.rate())
+    }
+
+    /// Number of blocks requested from the peer and not received yet.
+    pub fn in_flight(&self, peer: &SocketAddr) -> usize {
+        self.peers.get(peer).map_or(0, |s| s.in_flight.len())
+    }
+
+    pub fn queue_depth(&self, peer: &SocketAddr) -> usize {
+        self.peers
+            .get(peer)
+            .map_or(0, |s| self.config.queue_depth(s.rate.rate(), s.reqq))
+    }
+
+    /// Whether all that is left is waiting for requested blocks.
+    pub fn in_endgame(&self) -> bool {
+        self.picker.all_picked()
+            && self
+                .pieces
+                .values()
+                .all(|p| !p.blocks.contains(&BlockState::Missing))
+    }
+
+    /// Cancels the peer's timed out requests, and those another peer answered first in endgame,
+    /// then tops its queue up to its depth.
+    /// Blocks that just timed out are not asked of the same peer again, so another peer can pick them up.
+    /// Returns the `cancel` and `request` messages to send it.
+    pub fn requests(&mut self, peer: &SocketAddr) -> Vec<PeerMessage> {
+        let depth = self.queue_depth(peer);
+
+        let Some(state) = self.peers.get_mut(peer) else {
+            return Vec::new();
+        };
+
+        let now = Instant::now();
+        let timeout = self.config.request_timeout;
+
+        let (expired, in_flight) = std::mem::take(&mut state.in_flight)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, at)| now.duration_since(*at) >= timeout);
+
+        state.in_flight = in_flight;
+        let choked = state.choked;
+
+        let mut messages = std::mem::take(&mut state.cancels)
+            .iter()
+            .chain(expired.iter().map(|(b, _)| b))
+            .map(Block::cancel)
+            .collect::<Vec<_>>();
+
+        let stalled = expired.iter().map(|(b, _)| *b).collect::<Vec<_>>();
+        self.release(expired);
+
+        if choked {
+            return messages;
+        }
+
+        while self.peers[peer].in_flight.len() < depth {
+            let Some(block) = self.next_block(peer, &stalled) else {
+                break;
+            };
+
+            self.set_block_state(&block, BlockState::Requested);
+            self.peers
+                .get_mut(peer)
+                .unwrap()
+                .in_flight
+                .push((block, now));
+            messages.push(block.request());
+        }
+
+        messages
+    }
+
+    /// Stores a block from a `piece` message.
+    pub fn block_received(
+        &mut self,
+        peer: &SocketAddr,
+        piece: u32,
+        begin: u32,
+        data: &[u8],
+    ) -> Result<BlockOutcome, ProtocolError> {
+        if piece as usize >= self.picker.piece_count() {
+            return Err(ProtocolError::PieceOutOfRange(piece));
+        }
+
+        let block = Block {
+            piece,
+            begin,
+            length: data.len() as u32,
+        };
+
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.in_flight.retain(|(b, _)| *b != block);
+            state.rate.record(data.len());
+        }
+
+        // Endgame duplicates still on their way from other peers
+        for state in self.peers.values_mut() {
+            if state.in_flight.iter().any(|(b, _)| *b == block) {
+                state.in_flight.retain(|(b, _)| *b != block);
+                state.cancels.push(block);
+            }
+        }
+
+        let Some(partial) = self.pieces.get_mut(&piece) else {
+            return Ok(BlockOutcome::Unexpected);
+        };
+
+        let index = begin as usize / BLOCK_LEN;
+        let expected = Block::split(piece, partial.data.len()).nth(index);
+
+        if expected != Some(block) || partial.blocks[index] == BlockState::Received {
+            return Ok(BlockOutcome::Unexpected);
+        }
+
+        partial.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+        partial.blocks[index] = BlockState::Received;
+
+        if partial.blocks.iter().any(|b| *b != BlockState::Received) {
+            return Ok(BlockOutcome::Stored);
+        }
+
+        let partial = self.pieces.remove(&piece).unwrap();
+        let hash: [u8; 20] = Sha1::digest(&partial.data).into();
+
+        if Some(hash) == self.info.piece_hash(piece as usize) {
+            Ok(BlockOutcome::Completed {
+                piece,
+                data: partial.data,
+            })
+        } else {
+            self.picker.abort(piece as usize);
+
+            Ok(BlockOutcome::HashFailed(piece))
+        }
+    }
+
+    /// First block not yet requested of a piece in progress the peer has, or else of a newly picked piece.
+    /// In endgame, the block the peer has that the fewest other peers were asked for.
+    /// Blocks in `skip` are left for other peers.
+    fn next_block(&mut self, peer: &SocketAddr, skip: &[Block]) -> Option<Block> {
+        let started = self.pieces.iter().find_map(|(piece, partial)| {
+            if !self.picker.peer_has(peer, *piece as usize) {
+                return None;
+            }
+
+            Block::split(*piece, partial.data.len())
+                .zip(&partial.blocks)
+                .find(|(block, state)| **state == BlockState::Missing && !skip.contains(block))
+                .map(|(block, _)| block)
+        });
+
+        if started.is_some() {
+            return started;
+        }
+
+        if self.in_endgame() {
+            return self.endgame_block(peer, skip);
+        }
+
+        let piece = self.picker.pick(peer)?;
+        let size = self.info.piece_size(piece)?;
+
+        self.pieces.insert(
+            piece as u32,
+            PartialPiece {
+                data: vec![0; size],
+                blocks: vec![BlockState::Missing; size.div_ceil(BLOCK_LEN)],
+            },
+        );
+
+        Block::split(piece as u32, size).next()
+    }
+
+    fn endgame_block(&self, peer: &SocketAddr, skip: &[Block]) -> Option<Block> {
+        let own = &self.peers.get(peer)?.in_flight;
+        let requesters = |block: &Block| {
+            self.peers
+                .values()
+                .filter(|s| s.in_flight.iter().any(|(b, _)| b == block))
+                .count()
+        };
+
+        self.pieces
+            .iter()
+            .filter(|(piece, _)| self.picker.peer_has(peer, **piece as usize))
+            .flat_map(|(piece, partial)| {
+                Block::split(*piece, partial.data.len())
+                    .zip(&partial.blocks)
+                    .filter(|(_, state)| **state == BlockState::Requested)
+                    .map(|(block, _)| block)
+            })
+            .filter(|block| !skip.contains(block) && !own.iter().any(|(b, _)| b == block))
+            .min_by_key(requesters)
+    }
+
+    fn set_block_state(&mut self, block: &Block, state: BlockState) {
+        if let Some(partial) = self.pieces.get_mut(&block.piece) {
+            let index = block.begin as usize / BLOCK_LEN;
+
+            if partial.blocks[index] != BlockState::Received {
+                partial.blocks[index] = state;
+            }
+        }
+    }
+
+    /// Returns blocks to the pool, unless another peer was asked for them as well in endgame.
+    fn release(&mut self, blocks: Vec<(Block, Instant)>) {
+        for (block, _) in blocks {
+            let requested = self
+                .peers
+                .values()
+                .any(|s| s.in_flight.iter().any(|(b, _)| *b == block));
+
+            if !requested {
+                self.set_block_state(&block, BlockState::Missing);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::picker::Priority;
+    use crate::metainfo::DownloadTypes;
+
+    const PIECE_LEN: usize = 2 * BLOCK_LEN;
+    const TOTAL_LEN: usize = 2 * PIECE_LEN + 100;
+
+    fn content() -> Vec<u8> {
+        (0..TOTAL_LEN).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn pipeline() -> Pipeline {
+        let info = MetaInfo {
+            name: "test".to_string(),
+            piece_length: PIECE_LEN,
+            pieces: content()
+                .chunks(PIECE_LEN)
+                .flat_map(|p| <[u8; 20]>::from(Sha1::digest(p)))
+                .collect(),
+            files: DownloadTypes::Single { length: TOTAL_LEN },
+            leftovers: Default::default(),
+        };
+
+        let mut pipeline = Pipeline::new(Arc::new(info), PipelineConfig::default());
+        pipeline.picker_mut().set_random_first(0);
+
+        pipeline
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn unchoked_seed(pipeline: &mut Pipeline, peer: SocketAddr) {
+        pipeline.add_peer(peer);
+        pipeline
+            .picker_mut()
+            .bitfield(peer, &[0b1110_0000])
+            .unwrap();
+        pipeline.set_choked(&peer, false);
+    }
+
+    fn requested(messages: &[PeerMessage]) -> Vec<Block> {
+        messages
+            .iter()
+            .filter_map(|m| match m {
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => Some(Block {
+                    piece: *index,
+                    begin: *begin,
+                    length: *length,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn deliver(pipeline: &mut Pipeline, peer: &SocketAddr, block: Block) -> BlockOutcome {
+        let start = block.piece as usize * PIECE_LEN + block.begin as usize;
+        let data = &content()[start..start + block.length as usize];
+
+        pipeline
+            .block_received(peer, block.piece, block.begin, data)
+            .unwrap()
+    }
+
+    #[test]
+    fn block_split() {
+        let blocks = Block::split(3, BLOCK_LEN + 10).collect::<Vec<_>>();
+
+        assert_eq!(
+            blocks,
+            vec![
+                Block {
+                    piece: 3,
+                    begin: 0,
+                    length: BLOCK_LEN as u32
+                },
+                Block {
+                    piece: 3,
+                    begin: BLOCK_LEN as u32,
+                    length: 10
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pipeline_queue_depth() {
+        let config = PipelineConfig::default();
+
+        assert_eq!(config.queue_depth(0.0, None), 4);
+        assert_eq!(config.queue_depth(BLOCK_LEN as f64 * 10.0, None), 30);
+        assert_eq!(config.queue_depth(BLOCK_LEN as f64 * 10.0, Some(16)), 16);
+        assert_eq!(config.queue_depth(1e12, None), 250);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pipeline_download() {
+        let mut pipeline = pipeline();
+        let seed = peer(1);
+        unchoked_seed(&mut pipeline, seed);
+
+        // Choked peers get no requests
+        pipeline.set_choked(&seed, true);
+        assert!(pipeline.requests(&seed).is_empty());
+        pipeline.set_choked(&seed, false);
+
+        // Initial depth is 4 while the torrent has 5 blocks, a piece is finished before the next is started
+        let blocks = requested(&pipeline.requests(&seed));
+        assert_eq!(blocks.len(), 4);
+        assert!(pipeline.requests(&seed).is_empty());
+
+        let mut pieces = blocks.iter().map(|b| b.piece).collect::<Vec<_>>();
+        pieces.dedup();
+        let mut distinct = pieces.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(pieces.len(), distinct.len());
+
+        let mut completed = Vec::new();
+        let mut blocks = blocks;
+
+        while !blocks.is_empty() {
+            for block in blocks {
+                match deliver(&mut pipeline, &seed, block) {
+                    BlockOutcome::Completed { piece, data } => {
+                        assert!(!pipeline.picker().have_bitfield().get(piece as usize));
+                        pipeline.picker_mut().complete(piece as usize);
+                        completed.push((piece, data.len()));
+                    }
+                    outcome => assert_eq!(outcome, BlockOutcome::Stored),
+                }
+            }
+
+            blocks = requested(&pipeline.requests(&seed));
+        }
+
+        completed.sort();
+        assert_eq!(completed, vec![(0, PIECE_LEN), (1, PIECE_LEN), (2, 100)]);
+        assert!(pipeline.picker().is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pipeline_reqq_and_timeout() {
+        let mut pipeline = pipeline();
+        let seed = peer(1);
+        unchoked_seed(&mut pipeline, seed);
+        pipeline.set_reqq(&seed, 2);
+
+        // Pieces in a fixed order
+        pipeline.picker_mut().set_priority(0, Priority::High);
+        pipeline.picker_mut().set_priority(2, Priority::Skip);
+
+        let first = requested(&pipeline.requests(&seed));
+        assert_eq!(first.len(), 2);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(
+            deliver(&mut pipeline, &seed, first[0]),
+            BlockOutcome::Stored
+        );
+        let second = requested(&pipeline.requests(&seed));
+        assert_eq!(second.len(), 1);
+
+        // The stalled block is cancelled and left for another peer
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let messages = pipeline.requests(&seed);
+        assert_eq!(messages[0], first[1].cancel());
+        assert_eq!(requested(&messages).len(), 1);
+        assert!(!requested(&messages).contains(&first[1]));
+
+        let other = peer(2);
+        unchoked_seed(&mut pipeline, other);
+        assert_eq!(requested(&pipeline.requests(&other))[0], first[1]);
+
+        // Duplicates and blocks of pieces nobody asked for are dropped
+        assert_eq!(
+            deliver(&mut pipeline, &seed, first[0]),
+            BlockOutcome::Unexpected
+        );
+        assert!(matches!(
+            pipeline.block_received(&seed, 3, 0, &[0]),
+            Err(ProtocolError::PieceOutOfRange(3))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pipeline_hash_failure() {
+        let mut pipeline = pipeline();
+        let seed = peer(1);
+        unchoked_seed(&mut pipeline, seed);
+        pipeline.set_reqq(&seed, 1);
+
+        let block = requested(&pipeline.requests(&seed))[0];
+        let piece = block.piece;
+
+        let mut outcome = BlockOutcome::Unexpected;
+
+        for block in Block::split(piece, pipeline.info.piece_size(piece as usize).unwrap()) {
+            let garbage = vec![0xee; block.length as usize];
+            outcome = pipeline
+                .block_received(&seed, piece, block.begin, &garbage)
+                .unwrap();
+        }
+
+        assert_eq!(outcome, BlockOutcome::HashFailed(piece));
+        assert!(!pipeline.picker().have_bitfield().get(piece as usize));
+
+        // The piece goes back to the picker, its blocks to the pool
+        let other = peer(2);
+        unchoked_seed(&mut pipeline, other);
+        pipeline.remove_peer(&seed);
+        pipeline
+            .picker_mut()
+            .set_priority(piece as usize, Priority::High);
+        assert!(requested(&pipeline.requests(&other)).contains(&block));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pipeline_endgame() {
+        let mut pipeline = pipeline();
+        let (fast, slow) = (peer(1), peer(2));
+        unchoked_seed(&mut pipeline, slow);
+        unchoked_seed(&mut pipeline, fast);
+
+        let slow_blocks = requested(&pipeline.requests(&slow));
+        assert_eq!(slow_blocks.len(), 4);
+        assert!(!pipeline.in_endgame());
+
+        // The last block, then duplicates of what the slow peer is sitting on
+        let fast_blocks = requested(&pipeline.requests(&fast));
+        assert!(pipeline.in_endgame());
+        assert_eq!(fast_blocks.len(), 4);
+        assert!(!slow_blocks.contains(&fast_blocks[0]));
+        assert!(fast_blocks[1..].iter().all(|b| slow_blocks.contains(b)));
+
+        // First delivery wins, the other request is cancelled
+        let duplicate = fast_blocks[1];
+        assert_eq!(
+            deliver(&mut pipeline, &fast, duplicate),
+            BlockOutcome::Stored
+        );
+        let messages = pipeline.requests(&slow);
+        assert_eq!(messages[0], duplicate.cancel());
+        assert_eq!(requested(&messages), vec![fast_blocks[0]]);
+        assert_eq!(
+            deliver(&mut pipeline, &slow, duplicate),
+            BlockOutcome::Unexpected
+        );
+
+        // Blocks still requested elsewhere stay out of the pool when a peer chokes
+        pipeline.set_choked(&fast, true);
+        assert!(pipeline.in_endgame());
+
+        pipeline.set_choked(&slow, true);
+        assert!(!pipeline.in_endgame());
+    }
+}
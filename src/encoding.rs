@@ -1,15 +1,22 @@
 pub mod errors;
 pub mod types;
 
+/// Big endian `u32` at `offset` of a binary message, the caller checks the length beforehand.
+pub(crate) fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
 #[cfg(test)]
 mod tests {
     use super::types::{
         BTypes,
         BTypes::{ByteString, Dict, Integer, List, TextString},
+        DictInner,
     };
     use std::collections::BTreeMap;
 
     #[test]
+    #[allow(clippy::char_lit_as_u8)]
     fn btype_string() {
         const STRING_GOOD: [(&str, &str); 4] = [
             ("eggs", "4:eggs"),
@@ -51,6 +58,22 @@ mod tests {
             '6' as u8, ':' as u8, 0xb8, 0x9e, 0xaa, 0xc7, 'a' as u8, 'b' as u8,
         ];
         assert_eq!(Ok(ByteString(test[2..].to_owned())), BTypes::bdecode(&test));
+
+        // Length counts bytes, multi-byte characters must not run past it
+        test = vec![b'4', b':', 0xc3, 0xa9, 0xc3, 0xa9];
+        assert_eq!(Ok(TextString("éé".to_owned())), BTypes::bdecode(&test));
+
+        test = vec![b'l', b'2', b':', 0xc3, 0xa9, b'i', b'1', b'e', b'e'];
+        assert_eq!(
+            Ok(List(vec![TextString("é".to_owned()), Integer(1)])),
+            BTypes::bdecode(&test)
+        );
+
+        test = vec![b'l', b'1', b':', 0xc3, b'e'];
+        assert_eq!(
+            Ok(List(vec![ByteString(vec![0xc3])])),
+            BTypes::bdecode(&test)
+        );
     }
 
     #[test]
@@ -130,10 +153,10 @@ mod tests {
     #[test]
     fn btype_dict() {
         let dict = Dict({
-            let mut map: BTreeMap<String, BTypes> = BTreeMap::new();
+            let mut map: DictInner = BTreeMap::new();
 
-            map.insert("Breakfast".to_owned(), TextString("Beans".to_owned()));
-            map.insert("Servings".to_owned(), Integer(5));
+            map.insert("Breakfast".into(), TextString("Beans".to_owned()));
+            map.insert("Servings".into(), Integer(5));
 
             map
         });
@@ -144,11 +167,11 @@ mod tests {
         assert_eq!(BTypes::bdecode(&encoded.as_bytes().to_owned()), Ok(dict));
 
         let dict = Dict({
-            let mut map: BTreeMap<String, BTypes> = BTreeMap::new();
+            let mut map: DictInner = BTreeMap::new();
 
-            map.insert("Breakfast".to_owned(), TextString("Beans".to_owned()));
+            map.insert("Breakfast".into(), TextString("Beans".to_owned()));
             map.insert(
-                "Servings".to_owned(),
+                "Servings".into(),
                 List(vec![
                     Integer(-1),
                     TextString("eggs".to_owned()),
@@ -164,4 +187,23 @@ mod tests {
         assert_eq!(dict.bencode(), encoded.as_bytes().to_owned());
         assert_eq!(BTypes::bdecode(&encoded.as_bytes().to_owned()), Ok(dict));
     }
+
+    #[test]
+    fn btype_dict_byte_keys() {
+        let mut encoded = b"d1:ai2e2:".to_vec();
+        encoded.extend([0xff, 0xfe]);
+        encoded.extend(b"i1ee");
+
+        let dict = Dict({
+            let mut map: DictInner = BTreeMap::new();
+
+            map.insert(vec![0xff, 0xfe], Integer(1));
+            map.insert("a".into(), Integer(2));
+
+            map
+        });
+
+        assert_eq!(BTypes::bdecode(&encoded), Ok(dict.clone()));
+        assert_eq!(dict.bencode(), encoded);
+    }
 }
@@ -19,6 +19,8 @@ pub enum BencodingError {
     NotInt,
     NotByteStr,
     NotTextStr,
+    /// Integer under the key does not fit the type it is used as, e.g. a negative count.
+    IntOutOfRange(String),
 }
 
 impl std::fmt::Display for BencodingError {
@@ -46,6 +48,7 @@ impl std::fmt::Display for BencodingError {
             BencodingError::NotInt => write!(f, "Expected value not int"),
             BencodingError::NotByteStr => write!(f, "Expected value not byte string"),
             BencodingError::NotTextStr => write!(f, "Expected value not text string"),
+            BencodingError::IntOutOfRange(k) => write!(f, "Integer {k} out of range"),
         }
     }
 }
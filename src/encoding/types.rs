@@ -3,7 +3,11 @@ use std::{cmp::min, collections::BTreeMap, str::from_utf8};
 
 const BTYPE_PRINT_MAX_ITEMS: usize = 100;
 
-pub type DictInner = BTreeMap<String, BTypes>;
+/// Dictionary keys are byte strings, most are readable text but some (e.g. scrape `files`) are raw hashes.
+pub type DictInner = BTreeMap<Vec<u8>, BTypes>;
+
+/// Signature shared by the `keyed_*` helpers.
+pub type KeyedGetter<T> = fn(BTypes, &str) -> Result<(T, BTypes), BencodingError>;
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum BTypes {
@@ -27,6 +31,7 @@ impl std::fmt::Debug for BTypes {
 }
 
 impl BTypes {
+    #[allow(clippy::char_lit_as_u8)]
     pub fn bencode(&self) -> Vec<u8> {
         let mut output: Vec<u8> = Vec::new();
 
@@ -59,7 +64,7 @@ impl BTypes {
                 output.push('d' as u8);
 
                 for (key, value) in btree_map {
-                    output.extend(BTypes::TextString(key.to_owned()).bencode());
+                    output.extend(BTypes::ByteString(key.to_owned()).bencode());
                     output.extend(&value.bencode());
                 }
 
@@ -70,6 +75,7 @@ impl BTypes {
         output
     }
 
+    #[allow(clippy::needless_borrow)]
     pub fn bdecode(input: &Vec<u8>) -> Result<Self, BencodingError> {
         match bdecode(&input.as_slice()) {
             Ok((v, _)) => Ok(v),
@@ -77,6 +83,12 @@ impl BTypes {
         }
     }
 
+    /// Decodes the value at the front of `input` and returns whatever follows it,
+    /// for messages that carry raw data after a bencoded header.
+    pub fn bdecode_prefix(input: &[u8]) -> Result<(Self, &[u8]), BencodingError> {
+        bdecode(input)
+    }
+
     pub fn to_string(&self, indent: usize) -> String {
         let mut output: String = String::new();
 
@@ -110,7 +122,7 @@ impl BTypes {
                         format!(
                             "{}\"{}\": {}",
                             repeat(' ', indent + 2),
-                            k,
+                            String::from_utf8_lossy(k),
                             v.to_string(indent + 2)
                         )
                     })
@@ -160,6 +172,16 @@ impl BTypes {
         Ok(b)
     }
 
+    /// Accepts either string variant as raw bytes.
+    /// `bdecode` yields a `TextString` whenever binary data happens to be valid UTF-8.
+    pub fn expect_bytes(self) -> Result<Vec<u8>, BencodingError> {
+        match self {
+            BTypes::ByteString(b) => Ok(b),
+            BTypes::TextString(t) => Ok(t.into_bytes()),
+            _ => Err(BencodingError::NotByteStr),
+        }
+    }
+
     pub fn expect_int(self) -> Result<isize, BencodingError> {
         let BTypes::Integer(i) = self else {
             return Err(BencodingError::NotInt);
@@ -171,7 +193,7 @@ impl BTypes {
     pub fn keyed_dict(self, key: &str) -> Result<(DictInner, BTypes), BencodingError> {
         let mut d = self.expect_dict()?;
 
-        let Some(value) = d.remove(key) else {
+        let Some(value) = d.remove(key.as_bytes()) else {
             return Err(BencodingError::KeyNotFound(key.to_owned()));
         };
 
@@ -183,7 +205,7 @@ impl BTypes {
     pub fn keyed_list(self, key: &str) -> Result<(Vec<BTypes>, BTypes), BencodingError> {
         let mut d = self.expect_dict()?;
 
-        let Some(value) = d.remove(key) else {
+        let Some(value) = d.remove(key.as_bytes()) else {
             return Err(BencodingError::KeyNotFound(key.to_owned()));
         };
 
@@ -195,7 +217,7 @@ impl BTypes {
     pub fn keyed_text_str(self, key: &str) -> Result<(String, BTypes), BencodingError> {
         let mut d = self.expect_dict()?;
 
-        let Some(value) = d.remove(key) else {
+        let Some(value) = d.remove(key.as_bytes()) else {
             return Err(BencodingError::KeyNotFound(key.to_owned()));
         };
 
@@ -207,7 +229,7 @@ impl BTypes {
     pub fn keyed_byte_str(self, key: &str) -> Result<(Vec<u8>, BTypes), BencodingError> {
         let mut d = self.expect_dict()?;
 
-        let Some(value) = d.remove(key) else {
+        let Some(value) = d.remove(key.as_bytes()) else {
             return Err(BencodingError::KeyNotFound(key.to_owned()));
         };
 
@@ -216,10 +238,22 @@ impl BTypes {
         Ok((value, BTypes::Dict(d)))
     }
 
+    pub fn keyed_bytes(self, key: &str) -> Result<(Vec<u8>, BTypes), BencodingError> {
+        let mut d = self.expect_dict()?;
+
+        let Some(value) = d.remove(key.as_bytes()) else {
+            return Err(BencodingError::KeyNotFound(key.to_owned()));
+        };
+
+        let value = value.expect_bytes()?;
+
+        Ok((value, BTypes::Dict(d)))
+    }
+
     pub fn keyed_int(self, key: &str) -> Result<(isize, BTypes), BencodingError> {
         let mut d = self.expect_dict()?;
 
-        let Some(value) = d.remove(key) else {
+        let Some(value) = d.remove(key.as_bytes()) else {
             return Err(BencodingError::KeyNotFound(key.to_owned()));
         };
 
@@ -227,6 +261,26 @@ impl BTypes {
 
         Ok((value, BTypes::Dict(d)))
     }
+
+    /// Wraps one of the `keyed_*` helpers for keys that may be absent.
+    /// A missing key yields `None` and hands back the dict untouched.
+    pub fn keyed_optional<T>(
+        self,
+        key: &str,
+        keyed: KeyedGetter<T>,
+    ) -> Result<(Option<T>, BTypes), BencodingError> {
+        let BTypes::Dict(d) = &self else {
+            return Err(BencodingError::NotDict);
+        };
+
+        if !d.contains_key(key.as_bytes()) {
+            return Ok((None, self));
+        }
+
+        let (value, remainder) = keyed(self, key)?;
+
+        Ok((Some(value), remainder))
+    }
 }
 
 fn repeat(ch: char, count: usize) -> String {
@@ -247,6 +301,7 @@ fn bdecode(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     }
 }
 
+#[allow(clippy::char_lit_as_u8)]
 fn parse_integer(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     let Some((_, remainder)) = text.split_first() else {
         return Err(BencodingError::MissingInputType(text.to_owned()));
@@ -275,6 +330,7 @@ fn parse_integer(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     Ok((BTypes::Integer(number_value), remainder))
 }
 
+#[allow(clippy::char_lit_as_u8, clippy::iter_cloned_collect)]
 fn parse_string(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     let (string_length_slice, remainder) = split_on_delimiter(text, ':' as u8)?;
 
@@ -289,13 +345,24 @@ fn parse_string(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     let mut parsed_chars = Vec::new();
     let mut char_remainder = remainder;
 
-    for (ch, r) in UTF8ByteParser(char_remainder).take(string_length) {
+    // The length prefix counts bytes, not characters
+    for (ch, r) in UTF8ByteParser(remainder) {
+        let consumed = remainder.len() - r.len();
+
+        if consumed > string_length {
+            break;
+        }
+
         parsed_chars.push(ch);
         char_remainder = r;
+
+        if consumed == string_length {
+            break;
+        }
     }
 
-    // Check if successfully parsed all characters as utf-8, return text string
-    if parsed_chars.len() == string_length {
+    // Check if successfully parsed all bytes as utf-8, return text string
+    if remainder.len() - char_remainder.len() == string_length {
         return Ok((
             BTypes::TextString(parsed_chars.iter().collect()),
             char_remainder,
@@ -310,6 +377,7 @@ fn parse_string(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     Ok((BTypes::ByteString(sl.iter().cloned().collect()), r))
 }
 
+#[allow(clippy::char_lit_as_u8)]
 fn parse_list(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     let Some((_, remainder)) = text.split_first() else {
         return Err(BencodingError::MissingInputType(text.to_owned()));
@@ -332,12 +400,13 @@ fn parse_list(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     Ok((BTypes::List(values), remainder))
 }
 
+#[allow(clippy::char_lit_as_u8)]
 fn parse_dictionary(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
     let Some((_, remainder)) = text.split_first() else {
         return Err(BencodingError::MissingInputType(text.to_owned()));
     };
 
-    let mut map: BTreeMap<String, BTypes> = BTreeMap::new();
+    let mut map: DictInner = BTreeMap::new();
     let mut remainder = remainder;
 
     loop {
@@ -351,11 +420,11 @@ fn parse_dictionary(text: &[u8]) -> Result<(BTypes, &[u8]), BencodingError> {
 
         remainder = value_remainder;
 
-        if let BTypes::TextString(k) = key {
-            map.insert(k, value);
-        } else {
-            return Err(BencodingError::InvalidBType(key));
-        }
+        match key {
+            BTypes::TextString(k) => map.insert(k.into_bytes(), value),
+            BTypes::ByteString(k) => map.insert(k, value),
+            _ => return Err(BencodingError::InvalidBType(key)),
+        };
     }
 
     Ok((BTypes::Dict(map), remainder))
@@ -378,6 +447,7 @@ fn split_on_delimiter(input: &[u8], target_char: u8) -> Result<(&[u8], &[u8]), B
     Err(BencodingError::CharacterNotFound(target_char as char))
 }
 
+#[allow(clippy::get_first)]
 fn check_leader(input: &[u8], leader: u8) -> Result<bool, BencodingError> {
     let Some(first_char) = input.get(0) else {
         return Err(BencodingError::OutOfBounds);
@@ -403,6 +473,7 @@ impl<'a> Iterator for UTF8ByteParser<'a> {
 /// Returns the next valid UTF-8 character and the remainder of the slice.
 /// "Pops" the next char off the slice.
 /// Returns `None` if first 1-4 bytes are invalid UTF-8.
+#[allow(clippy::get_first)]
 fn next_char(input: &[u8]) -> Option<(char, &[u8])> {
     let b0 = *input.get(0)?;
     let cb0 = b0 as u32;
@@ -0,0 +1,9 @@
+pub mod dht;
+pub mod download;
+pub mod encoding;
+pub mod lsd;
+pub mod magnet;
+pub mod metainfo;
+pub mod network;
+pub mod storage;
+pub mod tracker;
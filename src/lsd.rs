@@ -0,0 +1,388 @@
+use crate::magnet::{decode_hex, encode_hex};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep_until};
+
+/// IPv4 multicast group of Local Service Discovery (BEP 14).
+pub const LSD_GROUP_V4: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771));
+
+/// IPv6 multicast group of Local Service Discovery, organization-local scope.
+pub const LSD_GROUP_V6: SocketAddr = SocketAddr::V6(SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+));
+
+/// Every torrent is announced this often.
+pub const LSD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A torrent is never announced more than once in this long, however often it is added.
+pub const LSD_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Info hashes per announce, keeps the datagram within a single Ethernet frame.
+const MAX_INFO_HASHES: usize = 16;
+
+/// Buffered commands and discovered peers before senders wait or drop.
+const CHANNEL_CAPACITY: usize = 64;
+
+const BT_SEARCH: &str = "BT-SEARCH * HTTP/1.1";
+
+/// A `BT-SEARCH` datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    /// Port the announcing peer accepts connections on.
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+
+    /// Random per client, lets the sender recognize its own announces coming back over multicast loop.
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn encode(&self, group: SocketAddr) -> Vec<u8> {
+        let mut text = format!("{BT_SEARCH}\r\nHost: {group}\r\nPort: {}\r\n", self.port);
+
+        for info_hash in &self.info_hashes {
+            text.push_str(&format!("Infohash: {}\r\n", encode_hex(info_hash)));
+        }
+
+        if let Some(cookie) = &self.cookie {
+            text.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+
+        text.push_str("\r\n\r\n");
+
+        text.into_bytes()
+    }
+
+    /// Header names are case insensitive, info hashes that are not 40 hex digits are skipped.
+    /// Returns `None` for anything but a `BT-SEARCH` with a port and at least one info hash.
+    pub fn decode(datagram: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(datagram).ok()?;
+        let mut lines = text.split("\r\n");
+
+        if lines.next()? != BT_SEARCH {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            let value = value.trim();
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    if let Some(info_hash) = decode_hex(value).and_then(|h| h.try_into().ok()) {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        if info_hashes.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LsdConfig {
+    /// Multicast group, `LSD_GROUP_V4` or `LSD_GROUP_V6`.
+    pub group: SocketAddr,
+
+    /// Port we accept peer connections on.
+    pub port: u16,
+}
+
+impl LsdConfig {
+    pub fn new(port: u16) -> Self {
+        Self {
+            group: LSD_GROUP_V4,
+            port,
+        }
+    }
+}
+
+/// Peer of one of our torrents found on the local network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LsdPeer {
+    pub addr: SocketAddr,
+    pub info_hash: [u8; 20],
+}
+
+enum LsdCommand {
+    Add([u8; 20]),
+    Remove([u8; 20]),
+    Stop,
+}
+
+/// Controls the Local Service Discovery task.
+pub struct LsdHandle {
+    commands: mpsc::Sender<LsdCommand>,
+    task: JoinHandle<()>,
+}
+
+impl LsdHandle {
+    /// Starts announcing a torrent and reporting peers that announce it.
+    pub async fn add(&self, info_hash: [u8; 20]) {
+        let _ = self.commands.send(LsdCommand::Add(info_hash)).await;
+    }
+
+    pub async fn remove(&self, info_hash: [u8; 20]) {
+        let _ = self.commands.send(LsdCommand::Remove(info_hash)).await;
+    }
+
+    pub async fn stop(self) {
+        let _ = self.commands.send(LsdCommand::Stop).await;
+        let _ = self.task.await;
+    }
+}
+
+/// Joins the multicast group and spawns the Local Service Discovery task.
+/// `info_hashes` are announced immediately, then every `LSD_INTERVAL`.
+/// Peers announcing one of our torrents are forwarded on the returned receiver, they are dropped when it is full.
+pub fn spawn_lsd(
+    config: LsdConfig,
+    info_hashes: Vec<[u8; 20]>,
+) -> std::io::Result<(LsdHandle, mpsc::Receiver<LsdPeer>)> {
+    let socket = bind_multicast(config.group)?;
+
+    let (command_tx, command_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let (peer_tx, peer_rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    let lsd = Lsd {
+        socket,
+        config,
+        cookie: format!("{:016x}", rand::random::<u64>()),
+        torrents: info_hashes.into_iter().map(|h| (h, None)).collect(),
+    };
+
+    let task = tokio::spawn(lsd.run(command_rx, peer_tx));
+
+    Ok((
+        LsdHandle {
+            commands: command_tx,
+            task,
+        },
+        peer_rx,
+    ))
+}
+
+fn bind_multicast(group: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+
+    // Every client on the host listens on the same port
+    socket.set_reuse_address(true)?;
+
+    match group.ip() {
+        IpAddr::V4(ip) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)?;
+            socket.set_multicast_ttl_v4(1)?;
+        }
+        IpAddr::V6(ip) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v6(&ip, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+struct Lsd {
+    socket: UdpSocket,
+    config: LsdConfig,
+    cookie: String,
+
+    /// Our torrents, with the time each was last announced.
+    torrents: BTreeMap<[u8; 20], Option<Instant>>,
+}
+
+impl Lsd {
+    async fn run(mut self, mut commands: mpsc::Receiver<LsdCommand>, peers: mpsc::Sender<LsdPeer>) {
+        let mut buffer = [0; 1500];
+        let mut next = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = sleep_until(next) => {
+                    let all = self.torrents.keys().copied().collect::<Vec<_>>();
+
+                    self.announce(&all).await;
+                    next = Instant::now() + LSD_INTERVAL;
+                }
+                command = commands.recv() => match command {
+                    Some(LsdCommand::Add(info_hash)) => {
+                        self.torrents.entry(info_hash).or_insert(None);
+                        self.announce(&[info_hash]).await;
+                    }
+                    Some(LsdCommand::Remove(info_hash)) => {
+                        self.torrents.remove(&info_hash);
+                    }
+                    Some(LsdCommand::Stop) | None => return,
+                },
+                received = self.socket.recv_from(&mut buffer) => {
+                    if let Ok((len, from)) = received {
+                        self.receive(&buffer[..len], from, &peers);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Announces the given torrents, skipping those announced within `LSD_MIN_INTERVAL`.
+    async fn announce(&mut self, info_hashes: &[[u8; 20]]) {
+        let now = Instant::now();
+
+        let due = info_hashes
+            .iter()
+            .filter(|h| match self.torrents.get_mut(*h) {
+                Some(last) if last.is_none_or(|l| now >= l + LSD_MIN_INTERVAL) => {
+                    *last = Some(now);
+                    true
+                }
+                _ => false,
+            })
+            .copied()
+            .collect::<Vec<_>>();
+
+        for chunk in due.chunks(MAX_INFO_HASHES) {
+            let announce = LsdAnnounce {
+                port: self.config.port,
+                info_hashes: chunk.to_vec(),
+                cookie: Some(self.cookie.clone()),
+            };
+
+            let _ = self
+                .socket
+                .send_to(&announce.encode(self.config.group), self.config.group)
+                .await;
+        }
+    }
+
+    fn receive(&self, datagram: &[u8], from: SocketAddr, peers: &mpsc::Sender<LsdPeer>) {
+        let Some(announce) = LsdAnnounce::decode(datagram) else {
+            return;
+        };
+
+        if announce.cookie.as_ref() == Some(&self.cookie) {
+            return;
+        }
+
+        let addr = SocketAddr::new(from.ip(), announce.port);
+
+        for info_hash in announce.info_hashes {
+            if self.torrents.contains_key(&info_hash) {
+                let _ = peers.try_send(LsdPeer { addr, info_hash });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsd_announce_round_trip() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("c00k1e".to_owned()),
+        };
+
+        let encoded = announce.encode(LSD_GROUP_V4);
+        assert!(encoded.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert!(encoded.ends_with(b"\r\n\r\n\r\n"));
+        assert_eq!(LsdAnnounce::decode(&encoded).unwrap(), announce);
+
+        let encoded = announce.encode(LSD_GROUP_V6);
+        assert!(encoded.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: [ff15::efc0:988f]:6771\r\n"));
+    }
+
+    #[test]
+    fn lsd_announce_decode() {
+        const SAMPLE: &[u8] =
+            b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nPORT: 51413\r\n\
+            infohash: ABABABABABABABABABABABABABABABABABABABAB\r\nInfohash: nothex\r\n\r\n\r\n";
+
+        assert_eq!(
+            LsdAnnounce::decode(SAMPLE).unwrap(),
+            LsdAnnounce {
+                port: 51413,
+                info_hashes: vec![[0xab; 20]],
+                cookie: None,
+            }
+        );
+
+        // No port, no valid info hash, not a search
+        assert!(LsdAnnounce::decode(b"BT-SEARCH * HTTP/1.1\r\nInfohash: abababababababababababababababababababab\r\n\r\n").is_none());
+        assert!(
+            LsdAnnounce::decode(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: ab\r\n\r\n")
+                .is_none()
+        );
+        assert!(LsdAnnounce::decode(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn lsd_discovery() {
+        // Not the real LSD port, so other clients on the host stay out of it
+        let group = SocketAddr::new(LSD_GROUP_V4.ip(), 16771 + rand::random::<u16>() % 1000);
+        let shared = [0x11; 20];
+
+        let (second, mut second_peers) =
+            spawn_lsd(LsdConfig { group, port: 2000 }, vec![]).unwrap();
+
+        // Only torrents we have are reported, and never our own announces
+        second.add(shared).await;
+
+        let (first, mut first_peers) =
+            spawn_lsd(LsdConfig { group, port: 1000 }, vec![shared, [0x22; 20]]).unwrap();
+
+        let wait = Duration::from_secs(5);
+
+        let found = tokio::time::timeout(wait, second_peers.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((found.addr.port(), found.info_hash), (1000, shared));
+
+        let found = tokio::time::timeout(wait, first_peers.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((found.addr.port(), found.info_hash), (2000, shared));
+
+        first.stop().await;
+        second.stop().await;
+
+        assert!(second_peers.recv().await.is_none());
+    }
+}
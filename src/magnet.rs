@@ -0,0 +1,351 @@
+use crate::encoding::types::BTypes;
+use crate::metainfo::Meta;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use std::str::FromStr;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+const BTMH_PREFIX: &str = "urn:btmh:";
+
+/// Everything except the URI unreserved characters is percent-encoded.
+const MAGNET_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MagnetError {
+    NotMagnet,
+    /// Neither a `urn:btih` nor a `urn:btmh` exact topic was given.
+    MissingInfoHash,
+    /// Exact topic whose hash is neither 40 hex nor 32 base32 characters.
+    BadInfoHash(String),
+    /// Parameter value that is not valid UTF-8 after percent-decoding.
+    BadEncoding(String),
+}
+
+impl std::fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MagnetError::NotMagnet => write!(f, "Link does not start with {MAGNET_PREFIX}"),
+            MagnetError::MissingInfoHash => write!(f, "Magnet link has no BitTorrent info hash"),
+            MagnetError::BadInfoHash(h) => write!(f, "Invalid info hash {h}"),
+            MagnetError::BadEncoding(p) => write!(f, "Invalid encoding in parameter {p}"),
+        }
+    }
+}
+
+impl std::error::Error for MagnetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+/// Magnet link of a torrent (BEP 9), v2 hashes from BEP 52.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MagnetLink {
+    /// v1 info hash, `xt=urn:btih:`.
+    pub info_hash: Option<[u8; 20]>,
+
+    /// v2 info hash as a multihash (hash function code, length, digest), `xt=urn:btmh:`.
+    pub info_hash_v2: Option<Vec<u8>>,
+
+    /// Suggested name, `dn`.
+    pub display_name: Option<String>,
+
+    /// Tracker URLs, `tr`.
+    pub trackers: Vec<String>,
+
+    /// Web seed URLs (BEP 19), `ws`.
+    pub web_seeds: Vec<String>,
+
+    /// Peers to connect to directly as `host:port`, `x.pe`.
+    pub peers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self, MagnetError> {
+        let Some(query) = strip_prefix_ignore_case(link, MAGNET_PREFIX) else {
+            return Err(MagnetError::NotMagnet);
+        };
+
+        let mut magnet = Self::default();
+
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+
+            let Ok(value) = percent_decode_str(value).decode_utf8() else {
+                return Err(MagnetError::BadEncoding(key.to_owned()));
+            };
+
+            let value = value.into_owned();
+
+            // Repeated parameters may be numbered, e.g. `tr.1`
+            let key = match key.rsplit_once('.') {
+                Some((base, n)) if n.bytes().all(|b| b.is_ascii_digit()) => base,
+                _ => key,
+            };
+
+            match key {
+                "xt" => magnet.exact_topic(&value)?,
+                "dn" => magnet.display_name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                "x.pe" => magnet.peers.push(value),
+                _ => (),
+            }
+        }
+
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(MagnetError::MissingInfoHash);
+        }
+
+        Ok(magnet)
+    }
+
+    fn exact_topic(&mut self, topic: &str) -> Result<(), MagnetError> {
+        let bad_hash = || MagnetError::BadInfoHash(topic.to_owned());
+
+        if let Some(hash) = strip_prefix_ignore_case(topic, BTIH_PREFIX) {
+            let hash = match hash.len() {
+                40 => decode_hex(hash),
+                32 => decode_base32(hash),
+                _ => None,
+            };
+
+            let hash = hash.and_then(|h| <[u8; 20]>::try_from(h).ok());
+            self.info_hash = Some(hash.ok_or_else(bad_hash)?);
+        } else if let Some(hash) = strip_prefix_ignore_case(topic, BTMH_PREFIX) {
+            self.info_hash_v2 = Some(decode_hex(hash).ok_or_else(bad_hash)?);
+        }
+
+        // Exact topics of other networks are not ours to reject
+        Ok(())
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl std::fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parameters = Vec::new();
+
+        if let Some(hash) = &self.info_hash {
+            parameters.push(format!("xt={BTIH_PREFIX}{}", encode_hex(hash)));
+        }
+
+        if let Some(hash) = &self.info_hash_v2 {
+            parameters.push(format!("xt={BTMH_PREFIX}{}", encode_hex(hash)));
+        }
+
+        if let Some(name) = &self.display_name {
+            parameters.push(format!("dn={}", utf8_percent_encode(name, MAGNET_ENCODE)));
+        }
+
+        for (key, values) in [
+            ("tr", &self.trackers),
+            ("ws", &self.web_seeds),
+            ("x.pe", &self.peers),
+        ] {
+            for value in values {
+                parameters.push(format!(
+                    "{key}={}",
+                    utf8_percent_encode(value, MAGNET_ENCODE)
+                ));
+            }
+        }
+
+        write!(f, "{MAGNET_PREFIX}{}", parameters.join("&"))
+    }
+}
+
+impl Meta {
+    /// Magnet link carrying the info hash, name, trackers and web seeds of the torrent.
+    pub fn to_magnet(&self) -> MagnetLink {
+        let web_seeds = match self.leftovers.get("url-list".as_bytes()) {
+            Some(BTypes::List(urls)) => urls
+                .iter()
+                .filter_map(|u| u.clone().expect_text_str().ok())
+                .collect(),
+            Some(BTypes::TextString(url)) => vec![url.clone()],
+            _ => Vec::new(),
+        };
+
+        MagnetLink {
+            info_hash: Some(self.info_hash()),
+            info_hash_v2: None,
+            display_name: Some(self.info.name.clone()),
+            trackers: self.trackers().tiers.concat(),
+            web_seeds,
+            peers: Vec::new(),
+        }
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = text.get(..prefix.len())?;
+
+    head.eq_ignore_ascii_case(prefix)
+        .then(|| &text[prefix.len()..])
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// RFC 4648 base32 without padding, as used by older magnet links.
+fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())?;
+
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{AnnounceList, sample_meta};
+
+    const HASH: [u8; 20] = [
+        0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9, 0xf5, 0x19, 0xb3, 0x35, 0xaa,
+        0x7c, 0x13, 0x67, 0xa8, 0x8a,
+    ];
+
+    #[test]
+    fn magnet_parse_hex() {
+        let magnet: MagnetLink = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Some+File%20%C3%A9&tr=udp%3A%2F%2Ftracker.example%3A6969&tr.1=http://b.example/announce&ws=https%3A%2F%2Fseed.example%2Ff&x.pe=10.0.0.1:6881&x.pe=[::1]:51413&foo=bar"
+            .parse()
+            .unwrap();
+
+        assert_eq!(magnet.info_hash, Some(HASH));
+        assert_eq!(magnet.display_name.as_deref(), Some("Some+File \u{e9}"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["udp://tracker.example:6969", "http://b.example/announce"]
+        );
+        assert_eq!(magnet.web_seeds, vec!["https://seed.example/f"]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881", "[::1]:51413"]);
+    }
+
+    #[test]
+    fn magnet_parse_base32() {
+        let magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(magnet.info_hash, Some(HASH));
+
+        let lower =
+            MagnetLink::parse("MAGNET:?xt=URN:BTIH:yex6dqdlxisuvhoj6um3gnnkpqjwpkek").unwrap();
+        assert_eq!(lower.info_hash, Some(HASH));
+    }
+
+    #[test]
+    fn magnet_parse_v2() {
+        let multihash = format!("1220{}", "ab".repeat(32));
+        let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btmh:{multihash}")).unwrap();
+
+        assert_eq!(magnet.info_hash, None);
+        assert_eq!(magnet.info_hash_v2, decode_hex(&multihash));
+    }
+
+    #[test]
+    fn magnet_parse_errors() {
+        assert_eq!(
+            MagnetLink::parse("http://example.com"),
+            Err(MagnetError::NotMagnet)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?dn=name"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert!(matches!(
+            MagnetLink::parse("magnet:?xt=urn:btih:c12fe1"),
+            Err(MagnetError::BadInfoHash(_))
+        ));
+        assert!(matches!(
+            MagnetLink::parse("magnet:?xt=urn:btih:zz2fe1c06bba254a9dc9f519b335aa7c1367a88a"),
+            Err(MagnetError::BadInfoHash(_))
+        ));
+    }
+
+    #[test]
+    fn magnet_round_trip() {
+        let magnet = MagnetLink {
+            info_hash: Some(HASH),
+            info_hash_v2: Some(vec![0x12, 0x20, 1, 2]),
+            display_name: Some("a name/with&odd=chars".to_owned()),
+            trackers: vec!["udp://tracker.example:6969/announce?x=1&y=2".to_owned()],
+            web_seeds: vec!["https://seed.example/".to_owned()],
+            peers: vec!["[::1]:51413".to_owned()],
+        };
+
+        let link = magnet.to_string();
+        assert!(link.starts_with(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&xt=urn:btmh:12200102&dn=a%20name%2Fwith%26odd%3Dchars"
+        ));
+        assert_eq!(MagnetLink::parse(&link).unwrap(), magnet);
+    }
+
+    #[test]
+    fn meta_to_magnet() {
+        let mut meta = Meta {
+            announce: "http://a.example/announce".to_owned(),
+            announce_list: Some(AnnounceList {
+                tiers: vec![
+                    vec!["http://a.example/announce".to_owned()],
+                    vec!["udp://b.example:80".to_owned()],
+                ],
+            }),
+            ..sample_meta()
+        };
+        meta.leftovers.insert(
+            "url-list".into(),
+            BTypes::List(vec![BTypes::TextString("https://seed.example/".to_owned())]),
+        );
+
+        let magnet = meta.to_magnet();
+
+        assert_eq!(magnet.info_hash, Some(meta.info_hash()));
+        assert_eq!(magnet.display_name.as_deref(), Some("sample"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["http://a.example/announce", "udp://b.example:80"]
+        );
+        assert_eq!(magnet.web_seeds, vec!["https://seed.example/"]);
+    }
+}
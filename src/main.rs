@@ -1,58 +1,529 @@
-mod encoding;
-mod metainfo;
-mod network;
-mod tracker;
-
-use encoding::{
-    errors::BencodingError,
-    types::BTypes::{self, ByteString, Dict, Integer, List, TextString},
-};
-use metainfo::*;
-use network::*;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::prelude::*;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tc::dht::{Dht, DhtConfig};
+use tc::download::choker::{Choker, ChokerConfig};
+use tc::download::pipeline::{BLOCK_LEN, BlockOutcome, Pipeline, PipelineConfig};
+use tc::lsd::{LsdConfig, spawn_lsd};
+use tc::network::{
+    connection::PeerConnection, errors::PeerError, extension::*, message::*, metadata::*, pex::*,
 };
-use tracker::*;
+use tc::storage::Storage;
+use tc::tracker::{announcer::*, multi::*};
+use tc::{encoding::types::BTypes, metainfo::*, network::*, tracker::*};
+use tokio::sync::{broadcast, mpsc, watch};
+
+/// Counters for the tracker, forwarded to the announcer as they change.
+#[derive(Debug, Clone, Copy, Default)]
+struct Progress {
+    stats: TransferStats,
+    finished: bool,
+}
+
+/// Shared by every peer task of the torrent.
+#[derive(Clone)]
+struct Swarm {
+    local: HandshakeInfo,
+    info: Arc<MetaInfo>,
+    peers: PeerSet,
+
+    /// Addresses being connected to, kept out of `peers` so pex only advertises established connections.
+    dialing: Arc<Mutex<HashSet<SocketAddr>>>,
+    discovered: mpsc::Sender<SocketAddr>,
+    dht: Dht,
+    pipeline: Arc<Mutex<Pipeline>>,
+    choker: Arc<Mutex<Choker>>,
+    storage: Arc<Storage>,
+    progress: watch::Sender<Progress>,
+
+    /// Pieces written to disk, announced to every peer with `have`.
+    haves: broadcast::Sender<u32>,
+}
+
+impl Swarm {
+    /// Connects to `addr` unless we already have, or are opening, a connection to it.
+    fn dial(&self, addr: SocketAddr, expected: Option<[u8; 20]>) {
+        if self.peers.contains(&addr) || !self.dialing.lock().unwrap().insert(addr) {
+            return;
+        }
+
+        let swarm = self.clone();
+
+        tokio::spawn(async move {
+            let result = PeerConnection::connect(addr, &swarm.local, expected).await;
+
+            // Moved to `peers` before leaving `dialing`, so a concurrent dial never sees neither
+            if result.is_ok() {
+                swarm.peers.insert(addr, PEX_FLAG_REACHABLE);
+            }
+
+            swarm.dialing.lock().unwrap().remove(&addr);
+
+            match result {
+                Ok(p) => {
+                    run_peer(p, addr, &swarm).await;
+                    swarm.peers.remove(&addr);
+                }
+                Err(e) => println!("Connecting to {addr} failed: {e}"),
+            }
+        });
+    }
+}
+
+async fn run_peer(mut peer: PeerConnection, addr: SocketAddr, swarm: &Swarm) {
+    println!("{:?}", &peer.remote);
+
+    // Subscribed before taking the bitfield, a piece finishing in between is at worst announced twice
+    let mut haves = swarm.haves.subscribe();
+    let have = swarm
+        .pipeline
+        .lock()
+        .unwrap()
+        .picker()
+        .have_bitfield()
+        .clone();
+
+    // The bitfield is optional while we have nothing
+    if have.count() > 0
+        && let Err(e) = peer
+            .send(PeerMessage::Bitfield(have.as_bytes().to_vec()))
+            .await
+    {
+        println!("{addr}: {e}");
+        return;
+    }
+
+    let mut extensions = ExtensionRegistry::new();
+    extensions.register(Box::new(MetadataExtension::serve(&swarm.info)));
+    extensions.register(Box::new(PexExtension::new(
+        swarm.peers.clone(),
+        addr,
+        swarm.discovered.clone(),
+    )));
+
+    if peer.capabilities.contains(Capabilities::EXTENSION_PROTOCOL) {
+        let handshake = PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: extensions.handshake().encode(),
+        };
+
+        if let Err(e) = peer.send(handshake).await {
+            println!("{addr}: {e}");
+            return;
+        }
+    }
+
+    if peer.capabilities.contains(Capabilities::DHT) {
+        let port = swarm.dht.local_addr().map(|a| a.port()).unwrap_or(0);
+
+        if let Err(e) = peer.send(PeerMessage::Port(port)).await {
+            println!("{addr}: {e}");
+            return;
+        }
+    }
+
+    swarm.pipeline.lock().unwrap().add_peer(addr);
+    swarm.choker.lock().unwrap().add_peer(addr);
+
+    let mut ticks = tokio::time::interval(Duration::from_secs(5));
+    let mut interested = false;
+    let mut choking = true;
+
+    loop {
+        let replies = tokio::select! {
+            message = peer.next() => match message {
+                Some(Ok(PeerMessage::Request { index, begin, length })) => {
+                    Ok(upload(swarm, addr, index, begin, length).await)
+                }
+                Some(Ok(m)) => handle_message(m, addr, swarm, &mut extensions),
+                Some(Err(e)) => Err(e),
+                None => break,
+            },
+            Ok(index) = haves.recv() => Ok(vec![PeerMessage::Have { index }]),
+            _ = ticks.tick() => Ok(extensions.tick()),
+        };
+
+        let result = match replies {
+            Ok(mut replies) => {
+                if replies.is_empty() && peer.keep_alive_due() {
+                    replies.push(PeerMessage::KeepAlive);
+                }
+
+                {
+                    let mut pipeline = swarm.pipeline.lock().unwrap();
+
+                    if pipeline.picker().is_interesting(&addr) != interested {
+                        interested = !interested;
+                        replies.push(if interested {
+                            PeerMessage::Interested
+                        } else {
+                            PeerMessage::NotInterested
+                        });
+                    }
+
+                    if swarm.choker.lock().unwrap().is_choked(&addr) != choking {
+                        choking = !choking;
+                        replies.push(if choking {
+                            PeerMessage::Choke
+                        } else {
+                            PeerMessage::Unchoke
+                        });
+                    }
+
+                    replies.extend(pipeline.requests(&addr));
+
+                    let requesting = pipeline.in_flight(&addr) > 0;
+                    swarm
+                        .choker
+                        .lock()
+                        .unwrap()
+                        .set_requesting(&addr, requesting);
+                }
+
+                let mut replies = futures_util::stream::iter(replies.into_iter().map(Ok));
+                peer.send_all(&mut replies).await
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            println!("{addr}: {e}");
+            break;
+        }
+    }
+
+    swarm.pipeline.lock().unwrap().remove_peer(&addr);
+    swarm.choker.lock().unwrap().remove_peer(&addr);
+}
+
+fn handle_message(
+    message: PeerMessage,
+    addr: SocketAddr,
+    swarm: &Swarm,
+    extensions: &mut ExtensionRegistry,
+) -> Result<Vec<PeerMessage>, PeerError> {
+    let mut pipeline = swarm.pipeline.lock().unwrap();
+
+    match message {
+        PeerMessage::Extended { id, payload } => {
+            let replies = extensions.handle(id, &payload)?;
+
+            if let Some(reqq) = extensions.peer.as_ref().and_then(|p| p.reqq) {
+                pipeline.set_reqq(&addr, reqq);
+            }
+
+            return Ok(replies);
+        }
+        PeerMessage::Bitfield(bits) => pipeline.picker_mut().bitfield(addr, &bits)?,
+        PeerMessage::Have { index } => pipeline.picker_mut().have(addr, index)?,
+        PeerMessage::Choke => pipeline.set_choked(&addr, true),
+        PeerMessage::Unchoke => pipeline.set_choked(&addr, false),
+        PeerMessage::Interested => swarm.choker.lock().unwrap().set_interested(&addr, true),
+        PeerMessage::NotInterested => swarm.choker.lock().unwrap().set_interested(&addr, false),
+        PeerMessage::Piece {
+            index,
+            begin,
+            block,
+        } => {
+            let outcome = pipeline.block_received(&addr, index, begin, &block)?;
+            let mut choker = swarm.choker.lock().unwrap();
+
+            if !matches!(outcome, BlockOutcome::Unexpected) {
+                choker.record_download(&addr, block.len());
+                swarm
+                    .progress
+                    .send_modify(|p| p.stats.downloaded += block.len());
+            }
+
+            match outcome {
+                BlockOutcome::Completed { piece, data } => {
+                    tokio::spawn(store_piece(swarm.clone(), piece, data));
+                }
+                BlockOutcome::HashFailed(piece) => {
+                    println!("{addr}: piece {piece} failed its hash")
+                }
+                _ => {}
+            }
+        }
+        PeerMessage::Port(port) => {
+            let dht = swarm.dht.clone();
+            let node = SocketAddr::new(addr.ip(), port);
+
+            tokio::spawn(async move { dht.ping(node).await });
+        }
+        m => println!("{addr}: {:?}", m),
+    }
+
+    Ok(Vec::new())
+}
+
+/// Reads a block the peer requested, with no lock held while the disk is busy.
+async fn upload(
+    swarm: &Swarm,
+    addr: SocketAddr,
+    index: u32,
+    begin: u32,
+    length: u32,
+) -> Vec<PeerMessage> {
+    // Requests that cross a choke or ask for pieces we lack are dropped
+    let allowed = {
+        let pipeline = swarm.pipeline.lock().unwrap();
+
+        !swarm.choker.lock().unwrap().is_choked(&addr)
+            && length as usize <= BLOCK_LEN
+            && pipeline.picker().have_bitfield().get(index as usize)
+    };
+
+    if !allowed {
+        return Vec::new();
+    }
+
+    let storage = swarm.storage.clone();
+    let read =
+        tokio::task::spawn_blocking(move || storage.read(index, begin, length as usize)).await;
+
+    match read {
+        Ok(Ok(block)) => {
+            swarm
+                .choker
+                .lock()
+                .unwrap()
+                .record_upload(&addr, block.len());
+            swarm
+                .progress
+                .send_modify(|p| p.stats.uploaded += block.len());
+
+            vec![PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            }]
+        }
+        Ok(Err(e)) => {
+            println!("{addr}: reading piece {index} failed: {e}");
+            Vec::new()
+        }
+        Err(e) => {
+            println!("{addr}: reading piece {index} panicked: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// Writes a verified piece, only then is it marked as had and served to peers.
+/// A failed write returns the piece to the picker to be downloaded again.
+async fn store_piece(swarm: Swarm, piece: u32, data: Vec<u8>) {
+    let storage = swarm.storage.clone();
+    let written = tokio::task::spawn_blocking(move || storage.write(piece, 0, &data)).await;
+
+    let mut pipeline = swarm.pipeline.lock().unwrap();
+
+    match written {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            println!("Writing piece {piece} failed: {e}");
+            pipeline.picker_mut().abort(piece as usize);
+            return;
+        }
+        Err(e) => {
+            println!("Writing piece {piece} panicked: {e}");
+            pipeline.picker_mut().abort(piece as usize);
+            return;
+        }
+    }
+
+    println!("Piece {piece} completed");
+    pipeline.picker_mut().complete(piece as usize);
+    let _ = swarm.haves.send(piece);
+
+    let finished = pipeline.picker().is_finished();
+    let size = swarm.info.piece_size(piece as usize).unwrap_or(0);
+
+    swarm.choker.lock().unwrap().set_seeding(finished);
+    swarm.progress.send_modify(|p| {
+        p.stats.left = p.stats.left.saturating_sub(size);
+        p.finished = finished;
+    });
+}
 
 async fn connection(info: Meta) {
     let port = 6881;
+    let peer_id = generate_peer_id();
 
-    let peer_id = tracker_get(&info, port, 0, 0, 20, TrackerEvent::Started).await;
+    let storage = match Storage::new(&info.info, ".") {
+        Ok(storage) => Arc::new(storage),
+        Err(e) => {
+            println!("Cannot store {}: {e}", info.info.name);
+            return;
+        }
+    };
 
-    // let listener = TcpListener::bind("0.0.0.0:6881").await.unwrap();
-    let listener = TcpListener::bind("0.0.0.0:6881").await.unwrap();
+    let mut tracker = MultiTracker::from_meta(&info);
 
-    let mut counter = 0;
+    match tracker.scrape(&[info.info_hash()]).await {
+        Ok(stats) => println!("{:?}", stats),
+        Err(e) => println!("Scrape failed: {e}"),
+    }
 
-    while counter < 5 {
-        let (mut socket, addr) = listener.accept().await.unwrap();
-        let mut buffer = Vec::new();
-        let _ = socket.read_to_end(&mut buffer).await.unwrap();
-        println!("{:?}", &buffer);
+    let stats = TransferStats {
+        left: info.info.total_length(),
+        ..TransferStats::default()
+    };
+
+    let (announcer, mut responses) = spawn_announcer(
+        tracker,
+        Arc::new(info.clone()),
+        peer_id,
+        port,
+        stats,
+        AnnounceOptions::new(),
+    );
 
-        // let _ = socket.write_all("HELLO".as_bytes());
-        let in_header = HandshakeInfo::decode(buffer).unwrap();
-        println!("{:?}", &in_header.encode());
+    let (discovered, mut pex_peers) = mpsc::channel(PEX_MAX_PEERS);
 
-        let out_header = HandshakeInfo {
-            peer_id: *b"abcdefghijklmnopqrst",
+    let dht = Dht::bind(DhtConfig {
+        state_path: Some("dht.state".into()),
+        ..DhtConfig::default()
+    })
+    .await
+    .unwrap();
+
+    let swarm = Swarm {
+        local: HandshakeInfo {
+            reserved: Capabilities::LOCAL,
+            peer_id,
             info_hash: info.info_hash(),
-        };
-        println!("{:?}", &out_header.encode());
+        },
+        info: Arc::new(info.info.clone()),
+        peers: PeerSet::new(),
+        dialing: Arc::new(Mutex::new(HashSet::new())),
+        discovered,
+        dht,
+        pipeline: Arc::new(Mutex::new(Pipeline::new(
+            Arc::new(info.info.clone()),
+            PipelineConfig::default(),
+        ))),
+        choker: Arc::new(Mutex::new(Choker::new(ChokerConfig::default()))),
+        storage,
+        progress: watch::Sender::new(Progress {
+            stats,
+            finished: false,
+        }),
+        // Every piece is sent once, so no peer can fall behind
+        haves: broadcast::Sender::new(info.info.piece_count().max(1)),
+    };
+
+    let choker = swarm.choker.clone();
 
-        if in_header.info_hash == out_header.info_hash {
-            let _ = socket.write_all(&out_header.encode().as_slice()).await;
-        } else {
-            dbg!("oof");
+    // Peer tasks pick up the new choke states on their next tick
+    tokio::spawn(async move {
+        let interval = choker.lock().unwrap().config().rechoke_interval;
+        let mut rechokes = tokio::time::interval(interval);
+
+        loop {
+            rechokes.tick().await;
+            choker.lock().unwrap().rechoke();
+        }
+    });
+
+    let outgoing = swarm.clone();
+
+    tokio::spawn(async move {
+        while let Some(response) = responses.recv().await {
+            let response = match response {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("Announce failed: {e}");
+                    continue;
+                }
+            };
+
+            println!("{:?}", response);
+
+            for peer in response.peers {
+                let expected = peer.peer_id.and_then(|id| id.try_into().ok());
+                outgoing.dial(peer.addr, expected);
+            }
+        }
+    });
+
+    let outgoing = swarm.clone();
+
+    tokio::spawn(async move {
+        while let Some(addr) = pex_peers.recv().await {
+            outgoing.dial(addr, None);
+        }
+    });
+
+    let outgoing = swarm.clone();
+
+    tokio::spawn(async move {
+        let dht = &outgoing.dht;
+
+        match dht.bootstrap().await {
+            Ok(n) => println!("DHT bootstrapped with {n} nodes"),
+            Err(e) => println!("DHT bootstrap failed: {e}"),
+        }
+
+        match dht.announce(outgoing.local.info_hash, port).await {
+            Ok(peers) => peers.into_iter().for_each(|p| outgoing.dial(p, None)),
+            Err(e) => println!("DHT announce failed: {e}"),
         }
 
-        counter += 1;
+        if let Err(e) = dht.save() {
+            println!("Saving DHT state failed: {e}");
+        }
+    });
+
+    let (lsd, mut lsd_peers) = spawn_lsd(LsdConfig::new(port), vec![info.info_hash()]).unwrap();
+    let outgoing = swarm.clone();
+
+    tokio::spawn(async move {
+        while let Some(peer) = lsd_peers.recv().await {
+            outgoing.dial(peer.addr, None);
+        }
+    });
+
+    let listener = bind_listener(port).unwrap();
+
+    let mut progress = swarm.progress.subscribe();
+    let mut finished = false;
+    let mut counter = 0;
+
+    while counter < 5 {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, addr) = accepted.unwrap();
+                let swarm = swarm.clone();
+
+                // Incoming peers connect from an ephemeral port, so they are not advertised over pex
+                tokio::spawn(async move {
+                    match PeerConnection::accept(socket, &swarm.local).await {
+                        Ok(p) => run_peer(p, addr, &swarm).await,
+                        Err(e) => println!("Handshake with {addr} failed: {e}"),
+                    }
+                });
+
+                counter += 1;
+            }
+            Ok(()) = progress.changed() => {
+                let current = *progress.borrow_and_update();
+                announcer.progress(current.stats);
+
+                if current.finished && !finished {
+                    finished = true;
+                    announcer.completed().await;
+                }
+            }
+        }
     }
 
-    let end = tracker_get(&info, port, 0, 0, 16384, TrackerEvent::Stopped).await;
+    announcer.stop().await;
+    lsd.stop().await;
 }
 
 #[tokio::main]
@@ -62,7 +533,5 @@ async fn main() {
     file.read_to_end(&mut contents).unwrap();
     let info = Meta::bdecode(BTypes::bdecode(&contents).unwrap()).unwrap();
 
-    const HEADER: &'static [u8] = "\x13BitTorrent protocol".as_bytes();
-
     connection(info).await;
 }
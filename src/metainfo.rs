@@ -1,4 +1,5 @@
 use crate::encoding::types::{BTypes::*, *};
+use rand::seq::SliceRandom;
 use sha1::{Digest, Sha1};
 use std::collections::BTreeMap;
 
@@ -51,11 +52,15 @@ pub struct Meta {
     /// Unofficially seems there can be multiple announce keys
     pub announce: String,
 
+    /// Tiers of backup trackers from `announce-list` (BEP 12).
+    /// When present, clients ignore `announce` in favour of these.
+    pub announce_list: Option<AnnounceList>,
+
     /// This maps to a dictionary, with keys described below.
     pub info: MetaInfo,
 
     /// Any unofficial leftover keys that might be needed for a hash but not functionality
-    pub leftovers: BTreeMap<String, BTypes>,
+    pub leftovers: DictInner,
 }
 
 impl Meta {
@@ -66,16 +71,120 @@ impl Meta {
         hasher.update(info.bencode().bencode());
         hasher.finalize().into()
     }
+
+    /// Trackers to announce to, `announce-list` if present, otherwise `announce` as a single tier.
+    pub fn trackers(&self) -> AnnounceList {
+        match &self.announce_list {
+            Some(list) => list.clone(),
+            None => AnnounceList {
+                tiers: vec![vec![self.announce.clone()]],
+            },
+        }
+    }
+}
+
+/// Torrent of a single 4 byte piece announcing to a local HTTP tracker, for tests.
+#[cfg(test)]
+pub(crate) fn sample_meta() -> Meta {
+    Meta {
+        announce: "http://127.0.0.1/announce".to_owned(),
+        announce_list: None,
+        info: MetaInfo {
+            name: "sample".to_owned(),
+            piece_length: 4,
+            pieces: vec![0; 20],
+            files: DownloadTypes::Single { length: 4 },
+            leftovers: BTreeMap::new(),
+        },
+        leftovers: BTreeMap::new(),
+    }
+}
+
+/// Tiers of tracker URLs (BEP 12).
+/// Trackers in a tier are tried in order before falling back to the next tier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceList {
+    pub tiers: Vec<Vec<String>>,
+}
+
+impl AnnounceList {
+    /// Randomises the order of trackers within each tier, done once when the list is first loaded.
+    pub fn shuffle(&mut self) {
+        let mut rng = rand::rng();
+
+        self.tiers
+            .iter_mut()
+            .for_each(|tier| tier.shuffle(&mut rng));
+    }
+
+    /// Moves a tracker that responded to the front of its tier.
+    pub fn promote(&mut self, tier: usize, index: usize) {
+        let Some(tier) = self.tiers.get_mut(tier) else {
+            return;
+        };
+
+        if index < tier.len() {
+            let url = tier.remove(index);
+            tier.insert(0, url);
+        }
+    }
+}
+
+impl Bencodeable for AnnounceList {
+    fn bencode(self) -> BTypes {
+        BTypes::List(
+            self.tiers
+                .into_iter()
+                .map(|tier| BTypes::List(tier.into_iter().map(TextString).collect()))
+                .collect(),
+        )
+    }
+
+    fn bdecode(input: BTypes) -> Result<Self, DataParseError>
+    where
+        Self: Sized,
+    {
+        let BTypes::List(list) = input else {
+            return Err(DataParseError::ExpectedList);
+        };
+
+        let mut tiers = Vec::new();
+
+        for tier in list {
+            let BTypes::List(tier) = tier else {
+                return Err(DataParseError::ExpectedList);
+            };
+
+            let mut urls = Vec::new();
+
+            for url in tier {
+                let BTypes::TextString(url) = url else {
+                    return Err(DataParseError::ExpectedTextString);
+                };
+
+                urls.push(url);
+            }
+
+            tiers.push(urls);
+        }
+
+        Ok(Self { tiers })
+    }
 }
 
 impl Bencodeable for Meta {
+    #[allow(clippy::needless_return)]
     fn bencode(self) -> BTypes {
         return BTypes::Dict({
-            let mut dict: BTreeMap<String, BTypes> = BTreeMap::new();
+            let mut dict: DictInner = BTreeMap::new();
 
-            dict.insert("announce".to_owned(), TextString(self.announce));
+            dict.insert("announce".into(), TextString(self.announce));
+
+            if let Some(list) = self.announce_list {
+                dict.insert("announce-list".into(), list.bencode());
+            }
 
-            dict.insert("info".to_owned(), self.info.bencode());
+            dict.insert("info".into(), self.info.bencode());
 
             dict.extend(self.leftovers); // untested
 
@@ -91,24 +200,30 @@ impl Bencodeable for Meta {
             return Err(DataParseError::ExpectedDict);
         };
 
-        let Some(BTypes::TextString(announce)) = dict.remove("announce") else {
+        let Some(BTypes::TextString(announce)) = dict.remove("announce".as_bytes()) else {
             return Err(DataParseError::BadKey(
                 "announce".to_owned(),
-                dict.get("announce").cloned(),
+                dict.get("announce".as_bytes()).cloned(),
             ));
         }; // IMRPOVE: Macro to do this
 
-        let Some(info) = dict.remove("info") else {
+        let Some(info) = dict.remove("info".as_bytes()) else {
             return Err(DataParseError::BadKey(
                 "info".to_owned(),
-                dict.get("info").cloned(),
+                dict.get("info".as_bytes()).cloned(),
             ));
         };
 
         let info = MetaInfo::bdecode(info)?;
 
+        let announce_list = match dict.remove("announce-list".as_bytes()) {
+            Some(list) => Some(AnnounceList::bdecode(list)?),
+            None => None,
+        };
+
         Ok(Self {
             announce,
+            announce_list,
             info,
             leftovers: dict,
         })
@@ -136,7 +251,76 @@ pub struct MetaInfo {
     pub files: DownloadTypes,
 
     /// Any unofficial leftover keys that might be needed for a hash but not functionality
-    pub leftovers: BTreeMap<String, BTypes>,
+    pub leftovers: DictInner,
+}
+
+impl MetaInfo {
+    /// Number of pieces, one per 20 byte hash in `pieces`.
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    /// Size of the whole download in bytes, all files concatenated.
+    pub fn total_length(&self) -> usize {
+        match &self.files {
+            DownloadTypes::Single { length } => *length,
+            DownloadTypes::Multiple { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    /// Length of a piece, every piece is `piece_length` long except a possibly shorter last one.
+    /// Returns `None` past the last piece.
+    pub fn piece_size(&self, index: usize) -> Option<usize> {
+        if index >= self.piece_count() {
+            return None;
+        }
+
+        let start = index * self.piece_length;
+
+        Some(
+            self.piece_length
+                .min(self.total_length().saturating_sub(start)),
+        )
+    }
+
+    /// Files of the download in order, with their place in the concatenated data.
+    /// Paths start with `name`, which is the file itself for a single file download and the directory otherwise.
+    pub fn file_spans(&self) -> Vec<FileSpan> {
+        match &self.files {
+            DownloadTypes::Single { length } => vec![FileSpan {
+                path: vec![self.name.clone()],
+                offset: 0,
+                length: *length,
+            }],
+            DownloadTypes::Multiple { files } => {
+                let mut offset = 0;
+
+                files
+                    .iter()
+                    .map(|f| {
+                        let span = FileSpan {
+                            path: std::iter::once(self.name.clone())
+                                .chain(f.path.iter().cloned())
+                                .collect(),
+                            offset,
+                            length: f.length,
+                        };
+
+                        offset += f.length;
+                        span
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Expected SHA1 hash of a piece.
+    pub fn piece_hash(&self, index: usize) -> Option<[u8; 20]> {
+        self.pieces
+            .get(index * 20..index * 20 + 20)?
+            .try_into()
+            .ok()
+    }
 }
 
 impl Bencodeable for MetaInfo {
@@ -144,21 +328,18 @@ impl Bencodeable for MetaInfo {
         BTypes::Dict({
             let mut dict = BTreeMap::new();
 
-            dict.insert("name".to_owned(), TextString(self.name));
+            dict.insert("name".into(), TextString(self.name));
 
-            dict.insert(
-                "piece length".to_owned(),
-                Integer(self.piece_length as isize),
-            );
+            dict.insert("piece length".into(), Integer(self.piece_length as isize));
 
-            dict.insert("pieces".to_owned(), ByteString(self.pieces));
+            dict.insert("pieces".into(), ByteString(self.pieces));
 
             match self.files {
                 DownloadTypes::Single { .. } => {
-                    dict.insert("length".to_owned(), self.files.bencode());
+                    dict.insert("length".into(), self.files.bencode());
                 }
                 DownloadTypes::Multiple { .. } => {
-                    dict.insert("files".to_owned(), self.files.bencode());
+                    dict.insert("files".into(), self.files.bencode());
                 }
             };
 
@@ -176,17 +357,17 @@ impl Bencodeable for MetaInfo {
             return Err(DataParseError::ExpectedDict);
         };
 
-        let Some(BTypes::TextString(name)) = dict.remove("name") else {
+        let Some(BTypes::TextString(name)) = dict.remove("name".as_bytes()) else {
             return Err(DataParseError::BadKey(
                 "info.name".to_owned(),
-                dict.get("name").cloned(),
+                dict.get("name".as_bytes()).cloned(),
             ));
         };
 
-        let Some(BTypes::Integer(piece_length)) = dict.remove("piece length") else {
+        let Some(BTypes::Integer(piece_length)) = dict.remove("piece length".as_bytes()) else {
             return Err(DataParseError::BadKey(
                 "info.piece length".to_owned(),
-                dict.get("piece length").cloned(),
+                dict.get("piece length".as_bytes()).cloned(),
             ));
         };
 
@@ -198,10 +379,11 @@ impl Bencodeable for MetaInfo {
         //    return Err(DataParseError::BadPieceLength(piece_length));
         //}
 
-        let Some(BTypes::ByteString(pieces)) = dict.remove("pieces") else {
+        // Hashes that happen to be valid UTF-8 decode as a text string
+        let Some(Ok(pieces)) = dict.remove("pieces".as_bytes()).map(BTypes::expect_bytes) else {
             return Err(DataParseError::BadKey(
                 "info.pieces".to_owned(),
-                dict.get("pieces").cloned(),
+                dict.get("pieces".as_bytes()).cloned(),
             ));
         };
 
@@ -254,8 +436,8 @@ impl DownloadTypes {
             return Err(DataParseError::ExpectedDict);
         };
 
-        let length = info.remove("length");
-        let files = info.remove("files");
+        let length = info.remove("length".as_bytes());
+        let files = info.remove("files".as_bytes());
 
         let download_type = match (length, files) {
             (None, None) => {
@@ -297,6 +479,17 @@ impl DownloadTypes {
     }
 }
 
+/// A file of the download, located within the concatenation of all files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Path components relative to the download directory, the last one being the file name.
+    pub path: Vec<String>,
+
+    /// Position of the first byte of the file in the concatenated data.
+    pub offset: usize,
+    pub length: usize,
+}
+
 /// Dictionary for use in multiple file downloads
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MultipleFileInner {
@@ -308,12 +501,24 @@ pub struct MultipleFileInner {
 }
 
 impl MultipleFileInner {
+    pub fn new(length: usize, path: Vec<String>) -> Self {
+        Self { length, path }
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn path(&self) -> &[String] {
+        &self.path
+    }
+
     fn bencode(self) -> BTypes {
         BTypes::Dict({
             let mut map = BTreeMap::new();
-            map.insert("length".to_owned(), BTypes::Integer(self.length as isize));
+            map.insert("length".into(), BTypes::Integer(self.length as isize));
             map.insert(
-                "path".to_owned(),
+                "path".into(),
                 BTypes::List(
                     self.path
                         .iter()
@@ -333,20 +538,20 @@ impl MultipleFileInner {
             return Err(DataParseError::ExpectedDict);
         };
 
-        let Some(BTypes::Integer(length)) = info.remove("length") else {
+        let Some(BTypes::Integer(length)) = info.remove("length".as_bytes()) else {
             return Err(DataParseError::BadKey(
                 "length".to_owned(),
-                info.get("length").cloned(),
+                info.get("length".as_bytes()).cloned(),
             ));
         };
 
         let path = {
             let mut path = Vec::new();
 
-            let Some(BTypes::List(path_list)) = info.remove("path") else {
+            let Some(BTypes::List(path_list)) = info.remove("path".as_bytes()) else {
                 return Err(DataParseError::BadKey(
                     "path".to_owned(),
-                    info.get("path").cloned(),
+                    info.get("path".as_bytes()).cloned(),
                 ));
             };
 
@@ -377,6 +582,7 @@ mod tests {
     fn single() {
         let test_value = Meta {
             announce: "www.example.com".to_string(),
+            announce_list: None,
             info: MetaInfo {
                 name: "The test file".to_string(),
                 piece_length: 4,
@@ -394,6 +600,7 @@ mod tests {
     fn multi() {
         let test_value = Meta {
             announce: "www.example.com".to_string(),
+            announce_list: None,
             info: MetaInfo {
                 name: "The test file".to_string(),
                 piece_length: 4,
@@ -418,4 +625,158 @@ mod tests {
 
         assert_eq!(Ok(test_value.clone()), Meta::bdecode(test_value.bencode()));
     }
+
+    #[test]
+    fn piece_sizes() {
+        let info = MetaInfo {
+            name: "The test file".to_string(),
+            piece_length: 32,
+            pieces: (0..60).collect(),
+            files: Multiple {
+                files: vec![
+                    MultipleFileInner {
+                        length: 50,
+                        path: vec!["a".to_string()],
+                    },
+                    MultipleFileInner {
+                        length: 20,
+                        path: vec!["b".to_string()],
+                    },
+                ],
+            },
+            leftovers: BTreeMap::new(),
+        };
+
+        assert_eq!(info.piece_count(), 3);
+        assert_eq!(info.total_length(), 70);
+        assert_eq!(info.piece_size(0), Some(32));
+        assert_eq!(info.piece_size(2), Some(6));
+        assert_eq!(info.piece_size(3), None);
+        assert_eq!(info.piece_hash(1).unwrap()[0], 20);
+        assert_eq!(info.piece_hash(3), None);
+    }
+
+    #[test]
+    fn file_spans() {
+        let mut info = MetaInfo {
+            name: "dir".to_string(),
+            piece_length: 32,
+            pieces: Vec::new(),
+            files: Multiple {
+                files: vec![
+                    MultipleFileInner::new(50, vec!["a".to_string()]),
+                    MultipleFileInner::new(0, vec!["empty".to_string()]),
+                    MultipleFileInner::new(20, vec!["sub".to_string(), "b".to_string()]),
+                ],
+            },
+            leftovers: BTreeMap::new(),
+        };
+
+        let spans = info.file_spans();
+        assert_eq!(
+            spans
+                .iter()
+                .map(|s| (s.offset, s.length))
+                .collect::<Vec<_>>(),
+            vec![(0, 50), (50, 0), (50, 20)]
+        );
+        assert_eq!(spans[2].path, vec!["dir", "sub", "b"]);
+
+        info.files = Single { length: 70 };
+        assert_eq!(
+            info.file_spans(),
+            vec![FileSpan {
+                path: vec!["dir".to_string()],
+                offset: 0,
+                length: 70,
+            }]
+        );
+    }
+
+    #[test]
+    fn announce_list() {
+        let test_value = Meta {
+            announce: "http://a.example.com/announce".to_string(),
+            announce_list: Some(AnnounceList {
+                tiers: vec![
+                    vec![
+                        "http://a.example.com/announce".to_string(),
+                        "udp://b.example.com:80".to_string(),
+                    ],
+                    vec!["http://c.example.com/announce".to_string()],
+                ],
+            }),
+            info: MetaInfo {
+                name: "The test file".to_string(),
+                piece_length: 4,
+                pieces: vec![0x12, 0x43, 0x76, 0xaf],
+                files: Single { length: 80 },
+                leftovers: BTreeMap::new(),
+            },
+            leftovers: BTreeMap::new(),
+        };
+
+        let decoded = Meta::bdecode(test_value.clone().bencode()).unwrap();
+
+        assert!(decoded.leftovers.is_empty());
+        assert_eq!(test_value, decoded);
+        assert_eq!(decoded.trackers(), test_value.announce_list.unwrap());
+    }
+
+    #[test]
+    fn announce_list_bad() {
+        let bad = List(vec![List(vec![Integer(1)])]);
+
+        assert_eq!(
+            AnnounceList::bdecode(bad),
+            Err(DataParseError::ExpectedTextString)
+        );
+        assert_eq!(
+            AnnounceList::bdecode(List(vec![Integer(1)])),
+            Err(DataParseError::ExpectedList)
+        );
+    }
+
+    #[test]
+    fn announce_list_promote() {
+        let mut list = AnnounceList {
+            tiers: vec![
+                vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                vec!["d".to_string()],
+            ],
+        };
+
+        list.promote(0, 2);
+        list.promote(5, 0);
+        list.promote(1, 3);
+
+        assert_eq!(list.tiers[0], vec!["c", "a", "b"]);
+        assert_eq!(list.tiers[1], vec!["d"]);
+
+        list.shuffle();
+
+        assert_eq!(list.tiers[0].len(), 3);
+        assert_eq!(list.tiers[1], vec!["d"]);
+    }
+
+    #[test]
+    fn trackers_single() {
+        let test_value = Meta {
+            announce: "http://a.example.com/announce".to_string(),
+            announce_list: None,
+            info: MetaInfo {
+                name: "The test file".to_string(),
+                piece_length: 4,
+                pieces: vec![0x12, 0x43, 0x76, 0xaf],
+                files: Single { length: 80 },
+                leftovers: BTreeMap::new(),
+            },
+            leftovers: BTreeMap::new(),
+        };
+
+        assert_eq!(
+            test_value.trackers().tiers,
+            vec![vec!["http://a.example.com/announce".to_string()]]
+        );
+    }
 }
@@ -1,26 +1,95 @@
+pub mod connection;
+pub mod errors;
+pub mod extension;
+pub mod message;
+pub mod metadata;
+pub mod pex;
+
+use crate::encoding::errors::BencodingError;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::TcpListener;
+
+#[allow(clippy::redundant_static_lifetimes)]
 const BT_HEADER: &'static [u8] = "\x13BitTorrent protocol".as_bytes();
 
+/// Size of an encoded handshake: header, 8 reserved bytes, info hash and peer id.
+pub const HANDSHAKE_LEN: usize = 68;
+
+/// Pending connection backlog for the peer listener
+const LISTEN_BACKLOG: i32 = 128;
+
 pub enum ProtocolError {
     NoBittorrentHeader,
     UnexpectedEnd,
     HeaderOverflow,
+    /// Message payload length, id byte included, does not fit its id.
+    BadMessageLength {
+        id: u8,
+        length: usize,
+    },
+    /// Message length prefix exceeds what we are willing to buffer.
+    MessageTooLarge(usize),
+    /// Message id of an extension the connection did not negotiate.
+    UnknownMessageId(u8),
+    /// Peer asked for a torrent other than the one the connection is for.
+    InfoHashMismatch,
+    /// Peer answered with a different peer id than the tracker gave us.
+    PeerIdMismatch,
+    /// Extended message sent to an id we never assigned.
+    UnknownExtension(u8),
+    /// Bencoded part of an extended message could not be decoded.
+    Bencoding(BencodingError),
+    /// Metadata exchange went wrong, e.g. the assembled info dict does not hash to the info hash.
+    BadMetadata(&'static str),
+    /// Peer exchange message with malformed peer lists.
+    BadPex(&'static str),
+    /// Bitfield does not have one bit per piece, or has spare bits set.
+    BadBitfield,
+    /// `have`, `request` or `piece` for a piece the torrent does not have.
+    PieceOutOfRange(u32),
 }
 
 impl std::fmt::Display for ProtocolError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.to_str())
+        match self {
+            Self::BadMessageLength { id, length } => write!(
+                f,
+                "Protocol Error: Message {id} cannot be {length} bytes long"
+            ),
+            Self::UnknownMessageId(id) => write!(f, "Protocol Error: Unknown message id {id}"),
+            Self::UnknownExtension(id) => write!(f, "Protocol Error: Unknown extension id {id}"),
+            Self::Bencoding(e) => write!(f, "Protocol Error: Bad extension message: {e}"),
+            Self::BadMetadata(m) | Self::BadPex(m) => write!(f, "Protocol Error: {m}"),
+            Self::PieceOutOfRange(index) => {
+                write!(f, "Protocol Error: Piece {index} is out of range")
+            }
+            Self::MessageTooLarge(length) => {
+                write!(f, "Protocol Error: Message of {length} bytes is too large")
+            }
+            _ => write!(f, "{}", self.to_str()),
+        }
     }
 }
 
 impl std::fmt::Debug for ProtocolError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.to_str())
+        write!(f, "{:?}", self.to_string())
     }
 }
 
 impl std::error::Error for ProtocolError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match self {
+            Self::Bencoding(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<BencodingError> for ProtocolError {
+    fn from(value: BencodingError) -> Self {
+        Self::Bencoding(value)
     }
 }
 
@@ -30,6 +99,17 @@ impl ProtocolError {
             Self::NoBittorrentHeader => "Protocol Error: No Bittorrent Header in handshake",
             Self::UnexpectedEnd => "Protocol Error: Header ended unexpectedly",
             Self::HeaderOverflow => "Protocol Error: Header length too long",
+            Self::BadMessageLength { .. } => "Protocol Error: Bad message length",
+            Self::MessageTooLarge(_) => "Protocol Error: Message too large",
+            Self::UnknownMessageId(_) => "Protocol Error: Unknown message id",
+            Self::InfoHashMismatch => "Protocol Error: Handshake is for a different torrent",
+            Self::PeerIdMismatch => "Protocol Error: Handshake has an unexpected peer id",
+            Self::UnknownExtension(_) => "Protocol Error: Unknown extension id",
+            Self::Bencoding(_) => "Protocol Error: Bad extension message",
+            Self::BadMetadata(_) => "Protocol Error: Bad metadata",
+            Self::BadPex(_) => "Protocol Error: Bad peer exchange message",
+            Self::BadBitfield => "Protocol Error: Bitfield does not match the piece count",
+            Self::PieceOutOfRange(_) => "Protocol Error: Piece out of range",
         }
     }
 }
@@ -56,13 +136,62 @@ fn match_bytes<'a>(baseline: &'a [u8], criteria: &[u8]) -> Option<&'a [u8]> {
     Some(&baseline[index..])
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// Features advertised in the 8 reserved bytes of a handshake.
+/// Bits we do not know about are kept, so a decoded handshake encodes back unchanged.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Capabilities([u8; 8]);
+
+impl Capabilities {
+    /// Extension protocol (BEP 10).
+    pub const EXTENSION_PROTOCOL: Self = Self::bit(5, 0x10);
+
+    /// Fast extension (BEP 6).
+    pub const FAST: Self = Self::bit(7, 0x04);
+
+    /// DHT, peers may send their DHT port (BEP 5).
+    pub const DHT: Self = Self::bit(7, 0x01);
+
+    /// Set in every handshake we send, extension protocol and DHT.
+    pub const LOCAL: Self = Self::from_bytes([0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+
+    const fn bit(byte: usize, mask: u8) -> Self {
+        let mut bytes = [0; 8];
+        bytes[byte] = mask;
+        Self(bytes)
+    }
+
+    pub const fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self(bytes)
+    }
+
+    pub const fn to_bytes(self) -> [u8; 8] {
+        self.0
+    }
+
+    /// Whether every bit of `other` is set.
+    pub fn contains(self, other: Self) -> bool {
+        self.intersection(other) == other
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+
+    /// Features both sides support, i.e. the ones a connection may use.
+    pub fn intersection(self, other: Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] & other.0[i]))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct HandshakeInfo {
+    pub reserved: Capabilities,
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
 
 impl HandshakeInfo {
+    #[allow(clippy::len_zero, clippy::manual_memcpy)]
     pub fn decode(received: Vec<u8>) -> Result<Self, ProtocolError> {
         let rs = received.as_slice();
 
@@ -71,8 +200,8 @@ impl HandshakeInfo {
             return Err(ProtocolError::NoBittorrentHeader);
         };
 
-        // Skip blank 8 bytes
-        let Some(rs) = rs.get(8..) else {
+        // Get reserved bytes
+        let Some((reserved, rs)) = rs.split_first_chunk::<8>() else {
             return Err(ProtocolError::UnexpectedEnd);
         };
 
@@ -98,7 +227,11 @@ impl HandshakeInfo {
             peer_id[i] = peer[i];
         }
 
-        Ok(Self { info_hash, peer_id })
+        Ok(Self {
+            reserved: Capabilities::from_bytes(*reserved),
+            info_hash,
+            peer_id,
+        })
     }
 
     pub fn encode(&self) -> Vec<u8> {
@@ -106,7 +239,10 @@ impl HandshakeInfo {
 
         BT_HEADER.iter().for_each(|v| buffer.push(*v));
 
-        (0..8).for_each(|_| buffer.push(0x00));
+        self.reserved
+            .to_bytes()
+            .iter()
+            .for_each(|v| buffer.push(*v));
 
         self.info_hash.iter().for_each(|v| buffer.push(*v));
 
@@ -116,6 +252,34 @@ impl HandshakeInfo {
     }
 }
 
+/// Binds the incoming peer listener on `port`.
+/// Prefers a dual-stack `[::]` socket so both IPv4 and IPv6 peers can connect,
+/// falling back to `0.0.0.0` on hosts without IPv6.
+pub fn bind_listener(port: u16) -> std::io::Result<TcpListener> {
+    let dual_stack = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+
+    match bind_socket(dual_stack) {
+        Ok(listener) => Ok(listener),
+        Err(_) => bind_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))),
+    }
+}
+
+fn bind_socket(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // Windows defaults to IPv6 only, Linux depends on a sysctl
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -152,6 +316,7 @@ mod tests {
         ];
 
         const RESULT: HandshakeInfo = HandshakeInfo {
+            reserved: Capabilities::EXTENSION_PROTOCOL,
             info_hash: [
                 58, 66, 135, 44, 34, 168, 10, 90, 45, 167, 115, 85, 60, 19, 130, 167, 61, 106, 204,
                 188,
@@ -175,6 +340,7 @@ mod tests {
         ];
 
         const SAMPLE: HandshakeInfo = HandshakeInfo {
+            reserved: Capabilities::from_bytes([0; 8]),
             info_hash: [
                 58, 66, 135, 44, 34, 168, 10, 90, 45, 167, 115, 85, 60, 19, 130, 167, 61, 106, 204,
                 188,
@@ -187,4 +353,46 @@ mod tests {
 
         assert_eq!(SAMPLE.encode(), RESULT.to_vec());
     }
+
+    #[test]
+    fn header_reserved_round_trip() {
+        let sample = HandshakeInfo {
+            reserved: Capabilities::from_bytes([0x80, 0, 0, 0, 0, 0x10, 0, 0x05]),
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+        };
+
+        let encoded = sample.encode();
+        assert_eq!(encoded[20..28], [0x80, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert_eq!(HandshakeInfo::decode(encoded).unwrap(), sample);
+    }
+
+    #[test]
+    fn capabilities_intersection() {
+        let ours = Capabilities::EXTENSION_PROTOCOL.union(Capabilities::DHT);
+        let theirs = Capabilities::from_bytes([0x80, 0, 0, 0, 0, 0x10, 0, 0x04]);
+
+        let negotiated = ours.intersection(theirs);
+
+        assert_eq!(negotiated, Capabilities::EXTENSION_PROTOCOL);
+        assert!(negotiated.contains(Capabilities::EXTENSION_PROTOCOL));
+        assert!(!negotiated.contains(Capabilities::DHT));
+        assert!(!negotiated.contains(Capabilities::FAST));
+        assert!(theirs.contains(Capabilities::FAST));
+        assert!(ours.contains(Capabilities::default()));
+    }
+
+    #[tokio::test]
+    async fn bind_listener_dual_stack() {
+        let listener = bind_listener(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let v4 = tokio::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await;
+        assert!(v4.is_ok());
+
+        if listener.local_addr().unwrap().is_ipv6() {
+            let v6 = tokio::net::TcpStream::connect((Ipv6Addr::LOCALHOST, port)).await;
+            assert!(v6.is_ok());
+        }
+    }
 }
@@ -0,0 +1,426 @@
+use super::errors::PeerError;
+use super::message::PeerMessage;
+use super::{Capabilities, HANDSHAKE_LEN, HandshakeInfo, ProtocolError};
+use bytes::{Buf, BytesMut};
+use futures_util::{Sink, Stream};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Sleep, sleep, timeout};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Time allowed for an outgoing TCP connection to be established.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time allowed for the peer to send its whole handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Peers send a keep-alive every two minutes, anything quieter than this is gone.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// We send a keep-alive after this long without sending anything, well within the peer's read timeout.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Largest message payload accepted by default.
+/// Fits a bitfield of 8 million pieces and any sane block size.
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// Splits a byte stream into `PeerMessage`s.
+pub struct PeerCodec {
+    /// Messages with a longer payload are rejected before they are buffered.
+    pub max_len: usize,
+
+    /// Negotiated features, messages of any other extension are rejected.
+    pub capabilities: Capabilities,
+}
+
+impl Decoder for PeerCodec {
+    type Item = PeerMessage;
+    type Error = PeerError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(length) = src.first_chunk::<4>() else {
+            return Ok(None);
+        };
+
+        let length = u32::from_be_bytes(*length) as usize;
+
+        if length > self.max_len {
+            return Err(ProtocolError::MessageTooLarge(length).into());
+        }
+
+        match PeerMessage::decode(src)? {
+            Some((message, used)) => {
+                message.check_capabilities(self.capabilities)?;
+                src.advance(used);
+                Ok(Some(message))
+            }
+            None => {
+                src.reserve(4 + length - src.len());
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<PeerMessage> for PeerCodec {
+    type Error = PeerError;
+
+    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item.encode());
+        Ok(())
+    }
+}
+
+/// Connection to a peer past the handshake.
+/// Yields incoming messages as a `Stream` and sends outgoing ones as a `Sink`.
+/// The stream ends with `PeerError::Timeout` once the peer stays silent for `read_timeout`.
+/// Keeping our side alive is up to the caller, see `keep_alive_due`.
+pub struct PeerConnection<S = TcpStream> {
+    framed: Framed<S, PeerCodec>,
+
+    /// Handshake the peer sent us.
+    pub remote: HandshakeInfo,
+
+    /// Features both sides advertised, only these may be used on this connection.
+    pub capabilities: Capabilities,
+
+    pub read_timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+
+    pub keep_alive_interval: Duration,
+
+    /// Last message handed to the sink, or when the connection was set up.
+    last_sent: Instant,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerConnection<S> {
+    /// Answers an incoming connection.
+    /// Reads the peer's handshake and replies with `local` if it asks for the torrent we serve.
+    pub async fn accept(mut stream: S, local: &HandshakeInfo) -> Result<Self, PeerError> {
+        let remote = read_handshake(&mut stream).await?;
+
+        if remote.info_hash != local.info_hash {
+            return Err(ProtocolError::InfoHashMismatch.into());
+        }
+
+        stream.write_all(&local.encode()).await?;
+
+        Ok(Self::new(stream, local, remote))
+    }
+
+    /// Opens the handshake on a connection we initiated.
+    /// The peer must answer for the same torrent and, when we know it, with the `expected` peer id.
+    pub async fn initiate(
+        mut stream: S,
+        local: &HandshakeInfo,
+        expected: Option<[u8; 20]>,
+    ) -> Result<Self, PeerError> {
+        stream.write_all(&local.encode()).await?;
+
+        let remote = read_handshake(&mut stream).await?;
+
+        if remote.info_hash != local.info_hash {
+            return Err(ProtocolError::InfoHashMismatch.into());
+        }
+
+        if expected.is_some_and(|id| id != remote.peer_id) {
+            return Err(ProtocolError::PeerIdMismatch.into());
+        }
+
+        Ok(Self::new(stream, local, remote))
+    }
+
+    /// Wraps a stream whose handshake has already been exchanged.
+    pub fn new(stream: S, local: &HandshakeInfo, remote: HandshakeInfo) -> Self {
+        let capabilities = local.reserved.intersection(remote.reserved);
+        let codec = PeerCodec {
+            max_len: MAX_MESSAGE_LEN,
+            capabilities,
+        };
+
+        Self {
+            framed: Framed::new(stream, codec),
+            capabilities,
+            remote,
+            read_timeout: READ_TIMEOUT,
+            deadline: Box::pin(sleep(READ_TIMEOUT)),
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+            last_sent: Instant::now(),
+        }
+    }
+
+    pub fn set_max_message_len(&mut self, max_len: usize) {
+        self.framed.codec_mut().max_len = max_len;
+    }
+
+    /// Whether we sent nothing for `keep_alive_interval` and owe the peer a keep-alive.
+    pub fn keep_alive_due(&self) -> bool {
+        self.last_sent.elapsed() >= self.keep_alive_interval
+    }
+
+    pub fn get_ref(&self) -> &S {
+        self.framed.get_ref()
+    }
+}
+
+impl PeerConnection<TcpStream> {
+    /// Dials a peer, e.g. one returned by a tracker, and performs the handshake.
+    pub async fn connect(
+        addr: SocketAddr,
+        local: &HandshakeInfo,
+        expected: Option<[u8; 20]>,
+    ) -> Result<Self, PeerError> {
+        let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(PeerError::Timeout),
+        };
+
+        stream.set_nodelay(true)?;
+
+        Self::initiate(stream, local, expected).await
+    }
+}
+
+/// Reads exactly one handshake, leaving anything the peer sent after it in the stream.
+pub async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<HandshakeInfo, PeerError> {
+    let mut buffer = [0; HANDSHAKE_LEN];
+
+    match timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut buffer)).await {
+        Ok(read) => read?,
+        Err(_) => return Err(PeerError::Timeout),
+    };
+
+    Ok(HandshakeInfo::decode(buffer.to_vec())?)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for PeerConnection<S> {
+    type Item = Result<PeerMessage, PeerError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.framed).poll_next(cx) {
+            Poll::Ready(item) => {
+                let next = Instant::now() + self.read_timeout;
+                self.deadline.as_mut().reset(next);
+
+                Poll::Ready(item)
+            }
+            Poll::Pending => match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Some(Err(PeerError::Timeout))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<PeerMessage> for PeerConnection<S> {
+    type Error = PeerError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.framed).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: PeerMessage) -> Result<(), Self::Error> {
+        self.last_sent = Instant::now();
+        Pin::new(&mut self.framed).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.framed).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.framed).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::duplex;
+
+    fn handshake(info_hash: u8, peer_id: u8) -> HandshakeInfo {
+        HandshakeInfo {
+            reserved: Capabilities::LOCAL,
+            info_hash: [info_hash; 20],
+            peer_id: [peer_id; 20],
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_accept() {
+        let (mut client, server) = duplex(1024);
+
+        // Handshake and first message arrive together, the connection stays open afterwards
+        let mut sent = handshake(1, 2).encode();
+        sent.extend(PeerMessage::Interested.encode());
+        client.write_all(&sent).await.unwrap();
+
+        let mut connection = PeerConnection::accept(server, &handshake(1, 3))
+            .await
+            .unwrap();
+        assert_eq!(connection.remote, handshake(1, 2));
+        assert_eq!(connection.capabilities, Capabilities::LOCAL);
+
+        let mut reply = [0; HANDSHAKE_LEN];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            HandshakeInfo::decode(reply.to_vec()).unwrap(),
+            handshake(1, 3)
+        );
+
+        assert_eq!(
+            connection.next().await.unwrap().unwrap(),
+            PeerMessage::Interested
+        );
+
+        connection.send(PeerMessage::Unchoke).await.unwrap();
+        let mut unchoke = [0; 5];
+        client.read_exact(&mut unchoke).await.unwrap();
+        assert_eq!(unchoke.to_vec(), PeerMessage::Unchoke.encode());
+    }
+
+    #[tokio::test]
+    async fn connection_wrong_info_hash() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(&handshake(9, 2).encode()).await.unwrap();
+
+        assert!(matches!(
+            PeerConnection::accept(server, &handshake(1, 3)).await,
+            Err(PeerError::Protocol(ProtocolError::InfoHashMismatch))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_read_timeout() {
+        let (_client, server) = duplex(1024);
+        let mut connection = PeerConnection::new(server, &handshake(1, 3), handshake(1, 2));
+
+        let start = Instant::now();
+        assert!(matches!(
+            connection.next().await,
+            Some(Err(PeerError::Timeout))
+        ));
+        assert_eq!(start.elapsed(), READ_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_keep_alive() {
+        let (mut client, server) = duplex(1024);
+        let mut connection = PeerConnection::new(server, &handshake(1, 3), handshake(1, 2));
+
+        tokio::time::advance(KEEP_ALIVE_INTERVAL - Duration::from_secs(1)).await;
+        assert!(!connection.keep_alive_due());
+
+        // Any message counts, the interval starts over
+        connection.send(PeerMessage::Interested).await.unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!connection.keep_alive_due());
+
+        tokio::time::advance(KEEP_ALIVE_INTERVAL).await;
+        assert!(connection.keep_alive_due());
+
+        connection.send(PeerMessage::KeepAlive).await.unwrap();
+        assert!(!connection.keep_alive_due());
+
+        let mut sent = [0; 9];
+        client.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent[5..], [0, 0, 0, 0]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_handshake_timeout() {
+        let (mut client, server) = duplex(1024);
+        client
+            .write_all(&handshake(1, 2).encode()[..30])
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            PeerConnection::accept(server, &handshake(1, 3)).await,
+            Err(PeerError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_max_message_len() {
+        let (mut client, server) = duplex(1024);
+        let mut connection = PeerConnection::new(server, &handshake(1, 3), handshake(1, 2));
+        connection.set_max_message_len(16);
+
+        client.write_all(&[0, 0, 0, 17]).await.unwrap();
+
+        assert!(matches!(
+            connection.next().await,
+            Some(Err(PeerError::Protocol(ProtocolError::MessageTooLarge(17))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_fast_not_negotiated() {
+        let (mut client, server) = duplex(1024);
+        let mut connection = PeerConnection::new(server, &handshake(1, 3), handshake(1, 2));
+
+        // Have all from the fast extension, which neither side advertised
+        client.write_all(&[0, 0, 0, 1, 0x0e]).await.unwrap();
+
+        assert!(matches!(
+            connection.next().await,
+            Some(Err(PeerError::Protocol(ProtocolError::UnknownMessageId(
+                0x0e
+            ))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut connection = PeerConnection::accept(socket, &handshake(1, 3))
+                .await
+                .unwrap();
+
+            assert_eq!(connection.remote, handshake(1, 2));
+            connection.next().await.unwrap().unwrap()
+        });
+
+        let mut connection = PeerConnection::connect(addr, &handshake(1, 2), Some([3; 20]))
+            .await
+            .unwrap();
+        assert_eq!(connection.remote, handshake(1, 3));
+
+        connection
+            .send(PeerMessage::Have { index: 4 })
+            .await
+            .unwrap();
+        assert_eq!(server.await.unwrap(), PeerMessage::Have { index: 4 });
+    }
+
+    #[tokio::test]
+    async fn connection_initiate_mismatch() {
+        let (mut server, client) = duplex(1024);
+        server.write_all(&handshake(1, 3).encode()).await.unwrap();
+
+        assert!(matches!(
+            PeerConnection::initiate(client, &handshake(1, 2), Some([4; 20])).await,
+            Err(PeerError::Protocol(ProtocolError::PeerIdMismatch))
+        ));
+
+        let (mut server, client) = duplex(1024);
+        server.write_all(&handshake(5, 3).encode()).await.unwrap();
+
+        assert!(matches!(
+            PeerConnection::initiate(client, &handshake(1, 2), None).await,
+            Err(PeerError::Protocol(ProtocolError::InfoHashMismatch))
+        ));
+    }
+}
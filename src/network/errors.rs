@@ -0,0 +1,43 @@
+use super::ProtocolError;
+
+#[derive(Debug)]
+pub enum PeerError {
+    /// Socket level failure, including the peer closing the connection mid handshake.
+    Io(std::io::Error),
+    /// Peer broke the wire protocol.
+    Protocol(ProtocolError),
+    /// Peer sent nothing, not even a keep-alive, within the read timeout.
+    Timeout,
+}
+
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerError::Io(e) => write!(f, "Peer socket error: {e}"),
+            PeerError::Protocol(e) => write!(f, "{e}"),
+            PeerError::Timeout => write!(f, "Peer timed out"),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PeerError::Io(e) => Some(e),
+            PeerError::Protocol(e) => Some(e),
+            PeerError::Timeout => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PeerError {
+    fn from(value: std::io::Error) -> Self {
+        PeerError::Io(value)
+    }
+}
+
+impl From<ProtocolError> for PeerError {
+    fn from(value: ProtocolError) -> Self {
+        PeerError::Protocol(value)
+    }
+}
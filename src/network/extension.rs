@@ -0,0 +1,353 @@
+use super::ProtocolError;
+use super::message::PeerMessage;
+use crate::encoding::types::{BTypes, DictInner};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Extended message id reserved for the extended handshake itself.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// Outstanding requests we let a peer queue, advertised as `reqq`.
+pub const LOCAL_REQUEST_QUEUE: usize = 250;
+
+/// Payload of the extended handshake (BEP 10).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message id the sender wants to receive them on.
+    pub m: BTreeMap<String, u8>,
+
+    /// Client name and version.
+    pub v: Option<String>,
+
+    /// Listen port of the sender.
+    pub p: Option<u16>,
+
+    /// Number of outstanding requests the sender accepts.
+    pub reqq: Option<usize>,
+
+    /// Address the sender sees us connecting from.
+    pub yourip: Option<IpAddr>,
+
+    /// Keys belonging to individual extensions, e.g. `metadata_size`.
+    pub leftovers: DictInner,
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = self.leftovers.clone();
+
+        let m = self
+            .m
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), BTypes::Integer(*id as isize)))
+            .collect();
+
+        dict.insert("m".into(), BTypes::Dict(m));
+
+        if let Some(v) = &self.v {
+            dict.insert("v".into(), BTypes::TextString(v.clone()));
+        }
+
+        if let Some(p) = self.p {
+            dict.insert("p".into(), BTypes::Integer(p as isize));
+        }
+
+        if let Some(reqq) = self.reqq {
+            dict.insert("reqq".into(), BTypes::Integer(reqq as isize));
+        }
+
+        if let Some(ip) = self.yourip {
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+
+            dict.insert("yourip".into(), BTypes::ByteString(bytes));
+        }
+
+        BTypes::Dict(dict).bencode()
+    }
+
+    /// Decodes a handshake, ignoring entries that are out of range rather than rejecting the peer.
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let dict = BTypes::bdecode(&payload.to_vec())?;
+
+        let (m, dict) = dict.keyed_optional("m", BTypes::keyed_dict)?;
+        let (v, dict) = dict.keyed_optional("v", BTypes::keyed_bytes)?;
+        let (p, dict) = dict.keyed_optional("p", BTypes::keyed_int)?;
+        let (reqq, dict) = dict.keyed_optional("reqq", BTypes::keyed_int)?;
+        let (yourip, dict) = dict.keyed_optional("yourip", BTypes::keyed_bytes)?;
+
+        let m = m
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, id)| {
+                let name = String::from_utf8(name).ok()?;
+                let id = u8::try_from(id.expect_int().ok()?).ok()?;
+
+                Some((name, id))
+            })
+            .collect();
+
+        let yourip = yourip.and_then(|ip| match ip.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?)),
+            _ => None,
+        });
+
+        Ok(Self {
+            m,
+            v: v.map(|v| String::from_utf8_lossy(&v).into_owned()),
+            p: p.and_then(|p| u16::try_from(p).ok()),
+            reqq: reqq.and_then(|r| usize::try_from(r).ok()),
+            yourip,
+            leftovers: dict.expect_dict()?,
+        })
+    }
+}
+
+/// A protocol carried inside extended messages, e.g. `ut_metadata`.
+/// Replies are returned as raw payloads, the registry addresses them to the peer's id for the extension.
+pub trait Extension: Send {
+    /// Key of the extension in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific keys to our extended handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called with every extended handshake of a peer that supports the extension.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    /// Called with the payload of every message the peer sends to the extension.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ProtocolError>;
+
+    /// Called periodically for extensions that send unprompted, e.g. `ut_pex`.
+    fn on_tick(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+/// Extensions of a single connection.
+/// Our ids are assigned in registration order starting at 1, the peer's are learnt from its handshake.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    remote: BTreeMap<String, u8>,
+
+    /// Last extended handshake received from the peer.
+    pub peer: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an extension and returns the id the peer should use for it.
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// Id the peer wants messages for `name` sent on, `None` if it does not support it.
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.get(name).copied()
+    }
+
+    /// Our extended handshake, ready for `p` or `yourip` to be filled in.
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            v: Some(format!("tc {}", env!("CARGO_PKG_VERSION"))),
+            reqq: Some(LOCAL_REQUEST_QUEUE),
+            ..Default::default()
+        };
+
+        for (index, extension) in self.extensions.iter().enumerate() {
+            handshake
+                .m
+                .insert(extension.name().to_owned(), index as u8 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+
+        handshake
+    }
+
+    /// Dispatches an extended message, returning the messages to send back.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<PeerMessage>, ProtocolError> {
+        if id == EXTENDED_HANDSHAKE_ID {
+            return Ok(self.handle_handshake(ExtendedHandshake::decode(payload)?));
+        }
+
+        let Some(extension) = self.extensions.get_mut(id as usize - 1) else {
+            return Err(ProtocolError::UnknownExtension(id));
+        };
+
+        let name = extension.name();
+        let replies = extension.on_message(payload)?;
+
+        Ok(self.address(name, replies))
+    }
+
+    /// Gives every extension the peer supports a chance to send, returning the messages to send.
+    pub fn tick(&mut self) -> Vec<PeerMessage> {
+        let mut messages = Vec::new();
+
+        for index in 0..self.extensions.len() {
+            let name = self.extensions[index].name();
+
+            if self.remote.contains_key(name) {
+                let payloads = self.extensions[index].on_tick();
+                messages.extend(self.address(name, payloads));
+            }
+        }
+
+        messages
+    }
+
+    fn handle_handshake(&mut self, handshake: ExtendedHandshake) -> Vec<PeerMessage> {
+        // Later handshakes only update the ids they mention, 0 disables an extension
+        for (name, id) in &handshake.m {
+            match id {
+                0 => self.remote.remove(name),
+                id => self.remote.insert(name.clone(), *id),
+            };
+        }
+
+        let mut messages = Vec::new();
+
+        for index in 0..self.extensions.len() {
+            let name = self.extensions[index].name();
+
+            if self.remote.contains_key(name) {
+                let replies = self.extensions[index].on_handshake(&handshake);
+                messages.extend(self.address(name, replies));
+            }
+        }
+
+        self.peer = Some(handshake);
+
+        messages
+    }
+
+    fn address(&self, name: &str, payloads: Vec<Vec<u8>>) -> Vec<PeerMessage> {
+        let Some(id) = self.remote_id(name) else {
+            return Vec::new();
+        };
+
+        payloads
+            .into_iter()
+            .map(|payload| PeerMessage::Extended { id, payload })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    /// Answers every message with its own payload reversed.
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+            handshake
+                .leftovers
+                .insert("echo_size".into(), BTypes::Integer(3));
+        }
+
+        fn on_handshake(&mut self, _handshake: &ExtendedHandshake) -> Vec<Vec<u8>> {
+            vec![b"hello".to_vec()]
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ProtocolError> {
+            Ok(vec![payload.iter().rev().copied().collect()])
+        }
+    }
+
+    #[test]
+    fn extended_handshake_decode_1() {
+        const SAMPLE: &[u8] =
+            b"d1:md11:LT_metadatai1e6:bad_idi300e6:ut_pexi2ee1:pi6881e4:reqqi500e1:v13:\xce\xbcTorrent 1.26:yourip4:\x7f\x00\x00\x01e";
+
+        let handshake = ExtendedHandshake::decode(SAMPLE).unwrap();
+
+        assert_eq!(
+            handshake.m,
+            BTreeMap::from([("LT_metadata".to_owned(), 1), ("ut_pex".to_owned(), 2)])
+        );
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.v.as_deref(), Some("\u{3bc}Torrent 1.2"));
+        assert_eq!(handshake.yourip, Some(IpAddr::from([127, 0, 0, 1])));
+        assert!(handshake.leftovers.is_empty());
+    }
+
+    #[test]
+    fn extended_handshake_round_trip() {
+        let mut handshake = ExtendedHandshake {
+            yourip: Some(Ipv6Addr::LOCALHOST.into()),
+            ..Default::default()
+        };
+        handshake.m.insert("ut_metadata".to_owned(), 3);
+        handshake.v = Some("tc".to_owned());
+        handshake.p = Some(51413);
+        handshake.reqq = Some(250);
+        handshake
+            .leftovers
+            .insert("metadata_size".into(), BTypes::Integer(31235));
+
+        assert_eq!(
+            ExtendedHandshake::decode(&handshake.encode()).unwrap(),
+            handshake
+        );
+    }
+
+    #[test]
+    fn registry_dispatch() {
+        let mut registry = ExtensionRegistry::new();
+        assert_eq!(registry.register(Box::new(Echo)), 1);
+
+        let ours = registry.handshake();
+        assert_eq!(ours.m["echo"], 1);
+        assert_eq!(ours.leftovers["echo_size".as_bytes()], BTypes::Integer(3));
+
+        // Nothing goes out before the peer told us its id
+        assert_eq!(registry.remote_id("echo"), None);
+
+        let mut theirs = ExtendedHandshake::default();
+        theirs.m.insert("echo".to_owned(), 7);
+
+        assert_eq!(
+            registry.handle(0, &theirs.encode()).unwrap(),
+            vec![PeerMessage::Extended {
+                id: 7,
+                payload: b"hello".to_vec()
+            }]
+        );
+
+        assert_eq!(
+            registry.handle(1, b"abc").unwrap(),
+            vec![PeerMessage::Extended {
+                id: 7,
+                payload: b"cba".to_vec()
+            }]
+        );
+
+        assert!(matches!(
+            registry.handle(2, b"abc"),
+            Err(ProtocolError::UnknownExtension(2))
+        ));
+
+        // Peer disables the extension again
+        theirs.m.insert("echo".to_owned(), 0);
+        assert!(registry.handle(0, &theirs.encode()).unwrap().is_empty());
+        assert!(registry.handle(1, b"abc").unwrap().is_empty());
+    }
+}
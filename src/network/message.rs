@@ -0,0 +1,415 @@
+use super::{Capabilities, ProtocolError};
+use crate::encoding::read_u32;
+
+/// Size of the big endian length prefix in front of every message.
+const LENGTH_PREFIX: usize = 4;
+
+const ID_CHOKE: u8 = 0;
+const ID_UNCHOKE: u8 = 1;
+const ID_INTERESTED: u8 = 2;
+const ID_NOT_INTERESTED: u8 = 3;
+const ID_HAVE: u8 = 4;
+const ID_BITFIELD: u8 = 5;
+const ID_REQUEST: u8 = 6;
+const ID_PIECE: u8 = 7;
+const ID_CANCEL: u8 = 8;
+const ID_PORT: u8 = 9;
+const ID_EXTENDED: u8 = 20;
+
+/// Ids of the fast extension messages (BEP 6), from suggest piece to allowed fast.
+const IDS_FAST: std::ops::RangeInclusive<u8> = 0x0d..=0x11;
+
+/// Messages exchanged with a peer after the handshake (BEP 3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    /// One bit per piece, high bit of the first byte is piece 0.
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// DHT listen port of the peer (BEP 5).
+    Port(u16),
+    /// Extension protocol message (BEP 10), `id` 0 is the extended handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// Message we do not implement, e.g. from the fast extension, kept so the connection can carry on.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl PeerMessage {
+    /// Encodes the message including its length prefix.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+
+        match self {
+            Self::KeepAlive => return vec![0; LENGTH_PREFIX],
+            Self::Choke => payload.push(ID_CHOKE),
+            Self::Unchoke => payload.push(ID_UNCHOKE),
+            Self::Interested => payload.push(ID_INTERESTED),
+            Self::NotInterested => payload.push(ID_NOT_INTERESTED),
+            Self::Have { index } => {
+                payload.push(ID_HAVE);
+                payload.extend(index.to_be_bytes());
+            }
+            Self::Bitfield(bits) => {
+                payload.push(ID_BITFIELD);
+                payload.extend(bits);
+            }
+            Self::Request {
+                index,
+                begin,
+                length,
+            } => {
+                payload.push(ID_REQUEST);
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(length.to_be_bytes());
+            }
+            Self::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.push(ID_PIECE);
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(block);
+            }
+            Self::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                payload.push(ID_CANCEL);
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(length.to_be_bytes());
+            }
+            Self::Port(port) => {
+                payload.push(ID_PORT);
+                payload.extend(port.to_be_bytes());
+            }
+            Self::Extended { id, payload: body } => {
+                payload.push(ID_EXTENDED);
+                payload.push(*id);
+                payload.extend(body);
+            }
+            Self::Unknown { id, payload: body } => {
+                payload.push(*id);
+                payload.extend(body);
+            }
+        }
+
+        let mut buffer = Vec::with_capacity(LENGTH_PREFIX + payload.len());
+        buffer.extend((payload.len() as u32).to_be_bytes());
+        buffer.extend(payload);
+
+        buffer
+    }
+
+    /// Decodes the message at the front of `buffer`.
+    /// Returns the message and the number of bytes it took up,
+    /// or `None` while `buffer` does not hold a whole message yet.
+    pub fn decode(buffer: &[u8]) -> Result<Option<(Self, usize)>, ProtocolError> {
+        let Some((length, rest)) = buffer.split_first_chunk::<LENGTH_PREFIX>() else {
+            return Ok(None);
+        };
+
+        let length = u32::from_be_bytes(*length) as usize;
+
+        let Some(payload) = rest.get(..length) else {
+            return Ok(None);
+        };
+
+        let message = Self::decode_payload(payload)?;
+
+        Ok(Some((message, LENGTH_PREFIX + length)))
+    }
+
+    /// Checks that the message belongs to an extension both sides advertised.
+    /// Fast extension messages without the fast bit must close the connection (BEP 6),
+    /// ids of no extension we know of pass as `Unknown`.
+    pub fn check_capabilities(&self, capabilities: Capabilities) -> Result<(), ProtocolError> {
+        let (id, required) = match self {
+            Self::Extended { .. } => (ID_EXTENDED, Capabilities::EXTENSION_PROTOCOL),
+            Self::Unknown { id, .. } if IDS_FAST.contains(id) => (*id, Capabilities::FAST),
+            _ => return Ok(()),
+        };
+
+        if capabilities.contains(required) {
+            Ok(())
+        } else {
+            Err(ProtocolError::UnknownMessageId(id))
+        }
+    }
+
+    /// Decodes a message with its length prefix already stripped.
+    pub fn decode_payload(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let Some((&id, body)) = payload.split_first() else {
+            return Ok(Self::KeepAlive);
+        };
+
+        let expect_len = |expected: usize| {
+            if body.len() == expected {
+                Ok(())
+            } else {
+                Err(ProtocolError::BadMessageLength {
+                    id,
+                    length: payload.len(),
+                })
+            }
+        };
+
+        let message = match id {
+            ID_CHOKE => expect_len(0).map(|_| Self::Choke)?,
+            ID_UNCHOKE => expect_len(0).map(|_| Self::Unchoke)?,
+            ID_INTERESTED => expect_len(0).map(|_| Self::Interested)?,
+            ID_NOT_INTERESTED => expect_len(0).map(|_| Self::NotInterested)?,
+            ID_HAVE => {
+                expect_len(4)?;
+
+                Self::Have {
+                    index: read_u32(body, 0),
+                }
+            }
+            ID_BITFIELD => Self::Bitfield(body.to_vec()),
+            ID_REQUEST | ID_CANCEL => {
+                expect_len(12)?;
+
+                let (index, begin, length) =
+                    (read_u32(body, 0), read_u32(body, 4), read_u32(body, 8));
+
+                if id == ID_REQUEST {
+                    Self::Request {
+                        index,
+                        begin,
+                        length,
+                    }
+                } else {
+                    Self::Cancel {
+                        index,
+                        begin,
+                        length,
+                    }
+                }
+            }
+            ID_PIECE => {
+                if body.len() < 8 {
+                    return Err(ProtocolError::BadMessageLength {
+                        id,
+                        length: payload.len(),
+                    });
+                }
+
+                Self::Piece {
+                    index: read_u32(body, 0),
+                    begin: read_u32(body, 4),
+                    block: body[8..].to_vec(),
+                }
+            }
+            ID_PORT => {
+                expect_len(2)?;
+
+                Self::Port(u16::from_be_bytes([body[0], body[1]]))
+            }
+            ID_EXTENDED => {
+                let Some((&extended_id, body)) = body.split_first() else {
+                    return Err(ProtocolError::BadMessageLength {
+                        id,
+                        length: payload.len(),
+                    });
+                };
+
+                Self::Extended {
+                    id: extended_id,
+                    payload: body.to_vec(),
+                }
+            }
+            _ => Self::Unknown {
+                id,
+                payload: body.to_vec(),
+            },
+        };
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let messages = [
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { index: 7 },
+            PeerMessage::Bitfield(vec![0b1010_0000, 0xff]),
+            PeerMessage::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0,
+                block: vec![1, 2, 3],
+            },
+            PeerMessage::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Port(6881),
+            PeerMessage::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+            PeerMessage::Unknown {
+                id: 0x0d,
+                payload: vec![0, 0, 0, 1],
+            },
+        ];
+
+        for message in messages {
+            let encoded = message.encode();
+
+            assert_eq!(
+                PeerMessage::decode(&encoded).unwrap(),
+                Some((message, encoded.len()))
+            );
+        }
+    }
+
+    #[test]
+    fn message_encode_1() {
+        assert_eq!(PeerMessage::KeepAlive.encode(), vec![0, 0, 0, 0]);
+        assert_eq!(
+            PeerMessage::Have { index: 0x0102 }.encode(),
+            vec![0, 0, 0, 5, 4, 0, 0, 1, 2]
+        );
+        assert_eq!(
+            PeerMessage::Port(0x1ae1).encode(),
+            vec![0, 0, 0, 3, 9, 0x1a, 0xe1]
+        );
+    }
+
+    #[test]
+    fn message_decode_partial() {
+        let encoded = PeerMessage::Have { index: 3 }.encode();
+
+        for end in 0..encoded.len() {
+            assert_eq!(PeerMessage::decode(&encoded[..end]).unwrap(), None);
+        }
+
+        // Bytes of the following message are left alone
+        let mut buffer = encoded.clone();
+        buffer.extend(PeerMessage::Choke.encode());
+        buffer.extend([0, 0]);
+
+        let (message, used) = PeerMessage::decode(&buffer).unwrap().unwrap();
+        assert_eq!(message, PeerMessage::Have { index: 3 });
+        assert_eq!(used, encoded.len());
+
+        let (message, used) = PeerMessage::decode(&buffer[used..]).unwrap().unwrap();
+        assert_eq!(message, PeerMessage::Choke);
+        assert_eq!(used, 5);
+    }
+
+    #[test]
+    fn message_decode_bad_length() {
+        assert!(matches!(
+            PeerMessage::decode(&[0, 0, 0, 2, 1, 0]),
+            Err(ProtocolError::BadMessageLength { id: 1, length: 2 })
+        ));
+        assert!(matches!(
+            PeerMessage::decode(&[0, 0, 0, 4, 4, 0, 0, 0]),
+            Err(ProtocolError::BadMessageLength { id: 4, length: 4 })
+        ));
+        assert!(matches!(
+            PeerMessage::decode(&[0, 0, 0, 5, 7, 0, 0, 0, 0]),
+            Err(ProtocolError::BadMessageLength { id: 7, length: 5 })
+        ));
+        assert!(matches!(
+            PeerMessage::decode(&[0, 0, 0, 1, 20]),
+            Err(ProtocolError::BadMessageLength { id: 20, length: 1 })
+        ));
+    }
+
+    #[test]
+    fn message_check_capabilities() {
+        let fast = PeerMessage::Unknown {
+            id: 0x0e,
+            payload: Vec::new(),
+        };
+        let extended = PeerMessage::Extended {
+            id: 0,
+            payload: b"de".to_vec(),
+        };
+        let unknown = PeerMessage::Unknown {
+            id: 99,
+            payload: Vec::new(),
+        };
+
+        assert!(matches!(
+            fast.check_capabilities(Capabilities::LOCAL),
+            Err(ProtocolError::UnknownMessageId(0x0e))
+        ));
+        assert!(fast.check_capabilities(Capabilities::FAST).is_ok());
+        assert!(matches!(
+            extended.check_capabilities(Capabilities::DHT),
+            Err(ProtocolError::UnknownMessageId(20))
+        ));
+        assert!(extended.check_capabilities(Capabilities::LOCAL).is_ok());
+        assert!(unknown.check_capabilities(Capabilities::default()).is_ok());
+        assert!(
+            PeerMessage::Port(6881)
+                .check_capabilities(Capabilities::default())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn message_decode_unknown_id() {
+        // Skipped over rather than failing the connection
+        assert_eq!(
+            PeerMessage::decode(&[0, 0, 0, 3, 99, 1, 2, 0, 0, 0, 0]).unwrap(),
+            Some((
+                PeerMessage::Unknown {
+                    id: 99,
+                    payload: vec![1, 2],
+                },
+                7
+            ))
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use super::ProtocolError;
+use super::extension::{ExtendedHandshake, Extension};
+use crate::encoding::types::{BTypes, DictInner};
+use crate::metainfo::{Bencodeable, MetaInfo};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use tokio::sync::oneshot;
+
+/// Name of the metadata extension in the extended handshake.
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Metadata is exchanged in pieces of this size, only the last may be shorter.
+pub const METADATA_PIECE_LEN: usize = 16384;
+
+/// Larger `metadata_size`s are refused rather than allocated.
+pub const MAX_METADATA_SIZE: usize = 1 << 24;
+
+const MSG_REQUEST: isize = 0;
+const MSG_DATA: isize = 1;
+const MSG_REJECT: isize = 2;
+
+/// Messages of the metadata extension (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict: DictInner = BTreeMap::new();
+
+        let (msg_type, piece) = match self {
+            Self::Request { piece } => (MSG_REQUEST, piece),
+            Self::Data { piece, .. } => (MSG_DATA, piece),
+            Self::Reject { piece } => (MSG_REJECT, piece),
+        };
+
+        dict.insert("msg_type".into(), BTypes::Integer(msg_type));
+        dict.insert("piece".into(), BTypes::Integer(*piece as isize));
+
+        if let Self::Data { total_size, .. } = self {
+            dict.insert("total_size".into(), BTypes::Integer(*total_size as isize));
+        }
+
+        let mut buffer = BTypes::Dict(dict).bencode();
+
+        // Piece data follows the dictionary unencoded
+        if let Self::Data { data, .. } = self {
+            buffer.extend(data);
+        }
+
+        buffer
+    }
+
+    /// Decodes a message, `None` for message types this version does not know.
+    pub fn decode(payload: &[u8]) -> Result<Option<Self>, ProtocolError> {
+        let (dict, data) = BTypes::bdecode_prefix(payload)?;
+
+        let (msg_type, dict) = dict.keyed_int("msg_type")?;
+        let (piece, dict) = dict.keyed_int("piece")?;
+
+        let Ok(piece) = usize::try_from(piece) else {
+            return Err(ProtocolError::BadMetadata("Negative metadata piece"));
+        };
+
+        let message = match msg_type {
+            MSG_REQUEST => Self::Request { piece },
+            MSG_DATA => {
+                let (total_size, _) = dict.keyed_int("total_size")?;
+
+                Self::Data {
+                    piece,
+                    total_size: total_size.max(0) as usize,
+                    data: data.to_vec(),
+                }
+            }
+            MSG_REJECT => Self::Reject { piece },
+            _ => return Ok(None),
+        };
+
+        Ok(Some(message))
+    }
+}
+
+/// Serves our info dict to peers and, while we lack it, downloads it from them.
+pub struct MetadataExtension {
+    info_hash: [u8; 20],
+
+    /// Bencoded info dict, once known.
+    metadata: Option<Vec<u8>>,
+
+    /// Pieces received so far, sized after the peer's `metadata_size`.
+    pieces: Vec<Option<Vec<u8>>>,
+    total_size: usize,
+
+    done: Option<oneshot::Sender<Result<MetaInfo, ProtocolError>>>,
+}
+
+impl MetadataExtension {
+    /// Extension for a torrent we already have the info dict of.
+    pub fn serve(info: &MetaInfo) -> Self {
+        let metadata = info.clone().bencode().bencode();
+
+        Self {
+            info_hash: Sha1::digest(&metadata).into(),
+            metadata: Some(metadata),
+            pieces: Vec::new(),
+            total_size: 0,
+            done: None,
+        }
+    }
+
+    /// Extension for a torrent only known by its info hash, e.g. from a magnet link.
+    /// The receiver yields the info dict once it has been downloaded and verified,
+    /// or an error once this peer rejected a piece or sent metadata not matching the hash, so another peer can be tried.
+    pub fn fetch(
+        info_hash: [u8; 20],
+    ) -> (Self, oneshot::Receiver<Result<MetaInfo, ProtocolError>>) {
+        let (done, receiver) = oneshot::channel();
+
+        let extension = Self {
+            info_hash,
+            metadata: None,
+            pieces: Vec::new(),
+            total_size: 0,
+            done: Some(done),
+        };
+
+        (extension, receiver)
+    }
+
+    fn piece_count(size: usize) -> usize {
+        size.div_ceil(METADATA_PIECE_LEN)
+    }
+
+    /// Gives up on fetching from this peer and tells the waiting fetch why.
+    fn fail(&mut self, reason: &'static str) -> ProtocolError {
+        self.pieces.clear();
+
+        if let Some(done) = self.done.take() {
+            let _ = done.send(Err(ProtocolError::BadMetadata(reason)));
+        }
+
+        ProtocolError::BadMetadata(reason)
+    }
+
+    fn on_data(
+        &mut self,
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    ) -> Result<(), ProtocolError> {
+        if self.metadata.is_some() {
+            return Ok(());
+        }
+
+        if total_size != self.total_size || piece >= self.pieces.len() {
+            return Err(self.fail("Unrequested metadata piece"));
+        }
+
+        let expected = METADATA_PIECE_LEN.min(total_size - piece * METADATA_PIECE_LEN);
+
+        if data.len() != expected {
+            return Err(self.fail("Wrong metadata piece length"));
+        }
+
+        self.pieces[piece] = Some(data);
+
+        if self.pieces.iter().any(Option::is_none) {
+            return Ok(());
+        }
+
+        let metadata = self
+            .pieces
+            .drain(..)
+            .flatten()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        if <[u8; 20]>::from(Sha1::digest(&metadata)) != self.info_hash {
+            return Err(self.fail("Metadata does not match info hash"));
+        }
+
+        let Some(info) = BTypes::bdecode(&metadata)
+            .ok()
+            .and_then(|b| MetaInfo::bdecode(b).ok())
+        else {
+            return Err(self.fail("Metadata is not an info dict"));
+        };
+
+        self.metadata = Some(metadata);
+
+        if let Some(done) = self.done.take() {
+            let _ = done.send(Ok(info));
+        }
+
+        Ok(())
+    }
+}
+
+impl Extension for MetadataExtension {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        if let Some(metadata) = &self.metadata {
+            handshake.leftovers.insert(
+                "metadata_size".into(),
+                BTypes::Integer(metadata.len() as isize),
+            );
+        }
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake) -> Vec<Vec<u8>> {
+        if self.metadata.is_some() || !self.pieces.is_empty() {
+            return Vec::new();
+        }
+
+        let Some(BTypes::Integer(size)) = handshake.leftovers.get("metadata_size".as_bytes())
+        else {
+            return Vec::new();
+        };
+
+        let size = *size as usize;
+
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Vec::new();
+        }
+
+        self.total_size = size;
+        self.pieces = vec![None; Self::piece_count(size)];
+
+        (0..self.pieces.len())
+            .map(|piece| MetadataMessage::Request { piece }.encode())
+            .collect()
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        let reply = match MetadataMessage::decode(payload)? {
+            Some(MetadataMessage::Request { piece }) => match &self.metadata {
+                Some(metadata) if piece < Self::piece_count(metadata.len()) => {
+                    let start = piece * METADATA_PIECE_LEN;
+                    let end = metadata.len().min(start + METADATA_PIECE_LEN);
+
+                    MetadataMessage::Data {
+                        piece,
+                        total_size: metadata.len(),
+                        data: metadata[start..end].to_vec(),
+                    }
+                }
+                _ => MetadataMessage::Reject { piece },
+            },
+            Some(MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            }) => {
+                self.on_data(piece, total_size, data)?;
+                return Ok(Vec::new());
+            }
+            // Peers without the whole info dict reject, the fetch moves on to another peer
+            Some(MetadataMessage::Reject { .. }) if self.metadata.is_none() => {
+                self.fail("Peer rejected a metadata request");
+                return Ok(Vec::new());
+            }
+            Some(MetadataMessage::Reject { .. }) | None => return Ok(Vec::new()),
+        };
+
+        Ok(vec![reply.encode()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{DownloadTypes, sample_meta};
+    use crate::network::extension::ExtensionRegistry;
+    use crate::network::message::PeerMessage;
+
+    /// Info dict spanning two metadata pieces.
+    fn sample_info() -> MetaInfo {
+        MetaInfo {
+            piece_length: 16384,
+            pieces: vec![0; 20 * 1000],
+            files: DownloadTypes::Single {
+                length: 16384 * 1000,
+            },
+            ..sample_meta().info
+        }
+    }
+
+    fn info_hash(info: &MetaInfo) -> [u8; 20] {
+        Sha1::digest(info.clone().bencode().bencode()).into()
+    }
+
+    /// Passes messages between two registries until neither has anything left to say.
+    fn exchange(a: &mut ExtensionRegistry, b: &mut ExtensionRegistry) -> Result<(), ProtocolError> {
+        let mut to_b = vec![PeerMessage::Extended {
+            id: 0,
+            payload: a.handshake().encode(),
+        }];
+        let mut to_a = vec![PeerMessage::Extended {
+            id: 0,
+            payload: b.handshake().encode(),
+        }];
+
+        while !to_a.is_empty() || !to_b.is_empty() {
+            let mut next_b = Vec::new();
+            let mut next_a = Vec::new();
+
+            for message in to_b.drain(..) {
+                if let PeerMessage::Extended { id, payload } = message {
+                    next_a.extend(b.handle(id, &payload)?);
+                }
+            }
+
+            for message in to_a.drain(..) {
+                if let PeerMessage::Extended { id, payload } = message {
+                    next_b.extend(a.handle(id, &payload)?);
+                }
+            }
+
+            to_a = next_a;
+            to_b = next_b;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_message_round_trip() {
+        let messages = [
+            MetadataMessage::Request { piece: 0 },
+            MetadataMessage::Data {
+                piece: 1,
+                total_size: 16390,
+                data: b"d4:spam".to_vec(),
+            },
+            MetadataMessage::Reject { piece: 2 },
+        ];
+
+        for message in messages {
+            assert_eq!(
+                MetadataMessage::decode(&message.encode()).unwrap(),
+                Some(message)
+            );
+        }
+
+        assert_eq!(
+            MetadataMessage::Request { piece: 0 }.encode(),
+            b"d8:msg_typei0e5:piecei0ee".to_vec()
+        );
+        assert_eq!(
+            MetadataMessage::decode(b"d8:msg_typei9e5:piecei0ee").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn metadata_fetch() {
+        let info = sample_info();
+
+        let mut seeder = ExtensionRegistry::new();
+        seeder.register(Box::new(MetadataExtension::serve(&info)));
+
+        let (fetch, mut done) = MetadataExtension::fetch(info_hash(&info));
+        let mut leecher = ExtensionRegistry::new();
+        leecher.register(Box::new(fetch));
+
+        exchange(&mut leecher, &mut seeder).unwrap();
+
+        assert_eq!(done.try_recv().unwrap().unwrap(), info);
+    }
+
+    #[test]
+    fn metadata_reject_without_info() {
+        let (fetch, _) = MetadataExtension::fetch([1; 20]);
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(fetch));
+
+        let mut theirs = ExtendedHandshake::default();
+        theirs.m.insert(UT_METADATA.to_owned(), 3);
+        registry.handle(0, &theirs.encode()).unwrap();
+
+        assert_eq!(
+            registry
+                .handle(1, &MetadataMessage::Request { piece: 0 }.encode())
+                .unwrap(),
+            vec![PeerMessage::Extended {
+                id: 3,
+                payload: MetadataMessage::Reject { piece: 0 }.encode()
+            }]
+        );
+    }
+
+    #[test]
+    fn metadata_hash_mismatch() {
+        let info = sample_info();
+
+        let mut seeder = ExtensionRegistry::new();
+        seeder.register(Box::new(MetadataExtension::serve(&info)));
+
+        let (fetch, mut done) = MetadataExtension::fetch([1; 20]);
+        let mut leecher = ExtensionRegistry::new();
+        leecher.register(Box::new(fetch));
+
+        assert!(matches!(
+            exchange(&mut leecher, &mut seeder),
+            Err(ProtocolError::BadMetadata(_))
+        ));
+        assert!(matches!(
+            done.try_recv(),
+            Ok(Err(ProtocolError::BadMetadata(_)))
+        ));
+    }
+
+    #[test]
+    fn metadata_fetch_rejected() {
+        let (fetch, mut done) = MetadataExtension::fetch([1; 20]);
+        let mut leecher = ExtensionRegistry::new();
+        leecher.register(Box::new(fetch));
+
+        let mut theirs = ExtendedHandshake::default();
+        theirs.m.insert(UT_METADATA.to_owned(), 3);
+        theirs
+            .leftovers
+            .insert("metadata_size".into(), BTypes::Integer(100));
+        assert_eq!(leecher.handle(0, &theirs.encode()).unwrap().len(), 1);
+
+        // The connection stays up, only the fetch fails
+        let reject = MetadataMessage::Reject { piece: 0 }.encode();
+        assert!(leecher.handle(1, &reject).unwrap().is_empty());
+        assert!(matches!(
+            done.try_recv(),
+            Ok(Err(ProtocolError::BadMetadata(_)))
+        ));
+    }
+}
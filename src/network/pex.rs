@@ -0,0 +1,357 @@
+use super::ProtocolError;
+use super::extension::Extension;
+use crate::encoding::types::{BTypes, DictInner};
+use crate::tracker::{COMPACT_PEER_LEN, COMPACT_PEER6_LEN, decode_compact, encode_compact};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Name of the peer exchange extension in the extended handshake.
+pub const UT_PEX: &str = "ut_pex";
+
+/// We send at most one message per peer this often.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages arriving sooner than this after the previous one are ignored.
+pub const PEX_MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+/// Added and dropped peers per message, in both directions.
+pub const PEX_MAX_PEERS: usize = 50;
+
+/// Peer prefers encrypted connections.
+pub const PEX_FLAG_ENCRYPTION: u8 = 0x01;
+/// Peer is a seed.
+pub const PEX_FLAG_SEED: u8 = 0x02;
+/// Peer supports uTP.
+pub const PEX_FLAG_UTP: u8 = 0x04;
+/// Peer supports the holepunch extension.
+pub const PEX_FLAG_HOLEPUNCH: u8 = 0x08;
+/// Peer accepted an incoming connection, so it is reachable.
+pub const PEX_FLAG_REACHABLE: u8 = 0x10;
+
+/// Payload of a `ut_pex` message (BEP 11).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PexMessage {
+    /// Peers connected since the last message, with their `PEX_FLAG_*` flags.
+    pub added: Vec<(SocketAddr, u8)>,
+
+    /// Peers disconnected since the last message.
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict: DictInner = BTreeMap::new();
+
+        let (added, added6): (Vec<_>, Vec<_>) = self.added.iter().partition(|(a, _)| a.is_ipv4());
+        let (dropped, dropped6): (Vec<_>, Vec<_>) = self.dropped.iter().partition(|a| a.is_ipv4());
+
+        for (key, peers) in [("added", added), ("added6", added6)] {
+            let compact = peers.iter().flat_map(|(a, _)| encode_compact(a)).collect();
+            let flags = peers.iter().map(|(_, f)| *f).collect();
+
+            dict.insert(key.into(), BTypes::ByteString(compact));
+            dict.insert(format!("{key}.f").into(), BTypes::ByteString(flags));
+        }
+
+        for (key, peers) in [("dropped", dropped), ("dropped6", dropped6)] {
+            let compact = peers.into_iter().flat_map(encode_compact).collect();
+
+            dict.insert(key.into(), BTypes::ByteString(compact));
+        }
+
+        BTypes::Dict(dict).bencode()
+    }
+
+    /// Decodes a message, missing keys count as empty and missing flags as 0.
+    pub fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let mut dict = BTypes::bdecode(&payload.to_vec())?.expect_dict()?;
+
+        let added = take_compact(&mut dict, "added", COMPACT_PEER_LEN)?;
+        let added_flags = take_bytes(&mut dict, "added.f")?;
+        let added6 = take_compact(&mut dict, "added6", COMPACT_PEER6_LEN)?;
+        let added6_flags = take_bytes(&mut dict, "added6.f")?;
+        let mut dropped = take_compact(&mut dict, "dropped", COMPACT_PEER_LEN)?;
+        dropped.extend(take_compact(&mut dict, "dropped6", COMPACT_PEER6_LEN)?);
+
+        let with_flags = |peers: Vec<SocketAddr>, flags: Vec<u8>| {
+            peers
+                .into_iter()
+                .enumerate()
+                .map(move |(i, a)| (a, flags.get(i).copied().unwrap_or(0)))
+        };
+
+        let added = with_flags(added, added_flags)
+            .chain(with_flags(added6, added6_flags))
+            .collect();
+
+        Ok(Self { added, dropped })
+    }
+}
+
+fn take_bytes(dict: &mut DictInner, key: &str) -> Result<Vec<u8>, ProtocolError> {
+    match dict.remove(key.as_bytes()) {
+        Some(b) => Ok(b.expect_bytes()?),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn take_compact(
+    dict: &mut DictInner,
+    key: &str,
+    entry_len: usize,
+) -> Result<Vec<SocketAddr>, ProtocolError> {
+    let bytes = take_bytes(dict, key)?;
+
+    decode_compact(&bytes, entry_len).ok_or(ProtocolError::BadPex("Bad compact peer list"))
+}
+
+/// Listen addresses of the peers we are connected to, shared by the pex extensions of every connection.
+#[derive(Debug, Clone, Default)]
+pub struct PeerSet {
+    peers: Arc<Mutex<BTreeMap<SocketAddr, u8>>>,
+}
+
+impl PeerSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, addr: SocketAddr, flags: u8) {
+        self.peers.lock().unwrap().insert(addr, flags);
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains_key(addr)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<SocketAddr, u8> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+/// Exchanges the peers of a `PeerSet` with a single peer.
+/// Newly learnt peers go to `discovered` for the caller to connect to, they are dropped when it is full.
+pub struct PexExtension {
+    swarm: PeerSet,
+
+    /// Address of the peer on the other end, never advertised back to it.
+    remote: SocketAddr,
+
+    /// Peers the remote has already heard about from us.
+    sent: BTreeSet<SocketAddr>,
+
+    discovered: mpsc::Sender<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexExtension {
+    pub fn new(swarm: PeerSet, remote: SocketAddr, discovered: mpsc::Sender<SocketAddr>) -> Self {
+        Self {
+            swarm,
+            remote,
+            sent: BTreeSet::new(),
+            discovered,
+            last_sent: None,
+            last_received: None,
+        }
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        let now = Instant::now();
+
+        if self
+            .last_received
+            .is_some_and(|last| now < last + PEX_MIN_RECEIVE_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+
+        self.last_received = Some(now);
+
+        let message = PexMessage::decode(payload)?;
+
+        for (addr, _) in message.added.into_iter().take(PEX_MAX_PEERS) {
+            if addr != self.remote && !self.swarm.contains(&addr) {
+                let _ = self.discovered.try_send(addr);
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn on_tick(&mut self) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+
+        if self.last_sent.is_some_and(|last| now < last + PEX_INTERVAL) {
+            return Vec::new();
+        }
+
+        let mut current = self.swarm.snapshot();
+        current.remove(&self.remote);
+
+        let added = current
+            .iter()
+            .filter(|(a, _)| !self.sent.contains(*a))
+            .take(PEX_MAX_PEERS)
+            .map(|(a, f)| (*a, *f))
+            .collect::<Vec<_>>();
+
+        let dropped = self
+            .sent
+            .iter()
+            .filter(|a| !current.contains_key(*a))
+            .take(PEX_MAX_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+
+        self.sent.extend(added.iter().map(|(a, _)| *a));
+        dropped.iter().for_each(|a| {
+            self.sent.remove(a);
+        });
+        self.last_sent = Some(now);
+
+        vec![PexMessage { added, dropped }.encode()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn pex_message_round_trip() {
+        let message = PexMessage {
+            added: vec![
+                (addr("10.0.0.1:6881"), PEX_FLAG_SEED),
+                (addr("10.0.0.2:6882"), 0),
+                (addr("[2001:db8::1]:51413"), PEX_FLAG_REACHABLE),
+            ],
+            dropped: vec![addr("10.0.0.3:6881"), addr("[2001:db8::2]:1")],
+        };
+
+        assert_eq!(PexMessage::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn pex_message_decode_missing_keys() {
+        const SAMPLE: &[u8] = b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e";
+
+        assert_eq!(
+            PexMessage::decode(SAMPLE).unwrap(),
+            PexMessage {
+                added: vec![(addr("10.0.0.1:6881"), 0)],
+                dropped: vec![],
+            }
+        );
+
+        assert!(matches!(
+            PexMessage::decode(b"d5:added5:\x0a\x00\x00\x01\x1ae"),
+            Err(ProtocolError::BadPex(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pex_send_interval() {
+        let swarm = PeerSet::new();
+        let remote = addr("10.0.0.9:6881");
+        swarm.insert(remote, 0);
+        swarm.insert(addr("10.0.0.1:6881"), PEX_FLAG_SEED);
+
+        let (tx, _rx) = mpsc::channel(8);
+        let mut pex = PexExtension::new(swarm.clone(), remote, tx);
+
+        let first = pex.on_tick();
+        assert_eq!(
+            PexMessage::decode(&first[0]).unwrap(),
+            PexMessage {
+                added: vec![(addr("10.0.0.1:6881"), PEX_FLAG_SEED)],
+                dropped: vec![],
+            }
+        );
+
+        // Changes wait for the interval
+        swarm.remove(&addr("10.0.0.1:6881"));
+        swarm.insert(addr("10.0.0.2:6881"), 0);
+        assert!(pex.on_tick().is_empty());
+
+        tokio::time::advance(PEX_INTERVAL).await;
+
+        let second = pex.on_tick();
+        assert_eq!(
+            PexMessage::decode(&second[0]).unwrap(),
+            PexMessage {
+                added: vec![(addr("10.0.0.2:6881"), 0)],
+                dropped: vec![addr("10.0.0.1:6881")],
+            }
+        );
+
+        // Nothing changed, nothing to send
+        tokio::time::advance(PEX_INTERVAL).await;
+        assert!(pex.on_tick().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pex_receive_rate_limit() {
+        let swarm = PeerSet::new();
+        let remote = addr("10.0.0.9:6881");
+        swarm.insert(addr("10.0.0.1:6881"), 0);
+
+        let (tx, mut rx) = mpsc::channel(128);
+        let mut pex = PexExtension::new(swarm, remote, tx);
+
+        let added = (0..60)
+            .map(|i| (addr(&format!("10.1.0.{i}:6881")), 0))
+            .chain([(addr("10.0.0.1:6881"), 0), (remote, 0)])
+            .collect();
+
+        pex.on_message(
+            &PexMessage {
+                added,
+                dropped: vec![],
+            }
+            .encode(),
+        )
+        .unwrap();
+
+        let mut discovered = Vec::new();
+        while let Ok(a) = rx.try_recv() {
+            discovered.push(a);
+        }
+        assert_eq!(discovered.len(), PEX_MAX_PEERS);
+
+        // Too soon after the previous message
+        let again = PexMessage {
+            added: vec![(addr("10.2.0.1:6881"), 0)],
+            dropped: vec![],
+        };
+        pex.on_message(&again.encode()).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        tokio::time::advance(PEX_MIN_RECEIVE_INTERVAL).await;
+        pex.on_message(&again.encode()).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), addr("10.2.0.1:6881"));
+    }
+}
@@ -0,0 +1,380 @@
+use crate::metainfo::{FileSpan, MetaInfo};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    /// File path from the metainfo that would escape the download directory.
+    BadPath(String),
+    /// Block that does not fit inside its piece.
+    OutOfRange {
+        piece: u32,
+        begin: u32,
+        length: usize,
+    },
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "Storage error: {e}"),
+            StorageError::BadPath(p) => write!(f, "Unsafe file path {p:?}"),
+            StorageError::OutOfRange {
+                piece,
+                begin,
+                length,
+            } => write!(
+                f,
+                "Block of {length} bytes at {begin} is outside of piece {piece}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(value: std::io::Error) -> Self {
+        StorageError::Io(value)
+    }
+}
+
+/// Part of a block that lands in a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Chunk {
+    file: usize,
+    file_offset: u64,
+
+    /// Bytes of the block stored in this file.
+    range: Range<usize>,
+}
+
+/// Reads and writes pieces of a download to its files under a directory.
+/// Pieces run across the files as if they were concatenated, so a block can straddle several files.
+/// Files are opened on first use and kept open, all IO is blocking.
+#[derive(Debug)]
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<FileSpan>,
+    piece_length: usize,
+    piece_count: usize,
+    total_length: usize,
+
+    /// Open handle of each file, a seek and the read or write after it happen under its lock.
+    handles: Vec<Mutex<Option<File>>>,
+}
+
+impl Storage {
+    /// Rejects file paths that are absolute or climb out of `root`, nothing is touched on disk yet.
+    pub fn new(info: &MetaInfo, root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let files = info.file_spans();
+
+        for file in &files {
+            let unsafe_component =
+                |c: &String| c.is_empty() || c == "." || c == ".." || c.contains(['/', '\\', '\0']);
+
+            if file.path.iter().any(unsafe_component) {
+                return Err(StorageError::BadPath(file.path.join("/")));
+            }
+        }
+
+        Ok(Self {
+            root: root.into(),
+            handles: files.iter().map(|_| Mutex::new(None)).collect(),
+            files,
+            piece_length: info.piece_length,
+            piece_count: info.piece_count(),
+            total_length: info.total_length(),
+        })
+    }
+
+    /// Directory the download's files are placed under.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn files(&self) -> &[FileSpan] {
+        &self.files
+    }
+
+    /// Where a file of the download lives on disk.
+    pub fn path(&self, file: usize) -> Option<PathBuf> {
+        let span = self.files.get(file)?;
+
+        Some(span.path.iter().fold(self.root.clone(), |p, c| p.join(c)))
+    }
+
+    /// Creates every file at its full length, existing data is kept.
+    /// Not required before writing, but empty files would otherwise never be created.
+    pub fn allocate(&self) -> Result<(), StorageError> {
+        for (i, span) in self.files.iter().enumerate() {
+            self.with_file(i, true, |file| {
+                if file.metadata()?.len() < span.length as u64 {
+                    file.set_len(span.length as u64)?;
+                }
+
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a block, e.g. a whole piece once it passed the hash check.
+    pub fn write(&self, piece: u32, begin: u32, data: &[u8]) -> Result<(), StorageError> {
+        for chunk in self.chunks(piece, begin, data.len())? {
+            self.with_file(chunk.file, true, |file| {
+                file.seek(SeekFrom::Start(chunk.file_offset))?;
+                file.write_all(&data[chunk.range])
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a block, failing if any of it was never written.
+    pub fn read(&self, piece: u32, begin: u32, length: usize) -> Result<Vec<u8>, StorageError> {
+        let mut data = vec![0; length];
+
+        for chunk in self.chunks(piece, begin, length)? {
+            self.with_file(chunk.file, false, |file| {
+                file.seek(SeekFrom::Start(chunk.file_offset))?;
+                file.read_exact(&mut data[chunk.range])
+            })?;
+        }
+
+        Ok(data)
+    }
+
+    /// Runs `f` on the open handle of a file, opening it first if needed.
+    /// Files missing on disk are only created when `create` is set, so reads of unwritten data fail.
+    fn with_file<R>(
+        &self,
+        file: usize,
+        create: bool,
+        f: impl FnOnce(&mut File) -> std::io::Result<R>,
+    ) -> Result<R, StorageError> {
+        // A panic mid read or write leaves the handle itself usable
+        let mut handle = self.handles[file]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if handle.is_none() {
+            *handle = Some(self.open(file, create)?);
+        }
+
+        Ok(f(handle.as_mut().unwrap())?)
+    }
+
+    fn open(&self, file: usize, create: bool) -> Result<File, StorageError> {
+        let path = self.path(file).expect("file index from chunks");
+
+        if create && let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(path)?)
+    }
+
+    /// Splits a block along file boundaries, skipping empty files.
+    fn chunks(&self, piece: u32, begin: u32, length: usize) -> Result<Vec<Chunk>, StorageError> {
+        let start = piece as usize * self.piece_length + begin as usize;
+        let end = start + length;
+        let piece_end = ((piece as usize + 1) * self.piece_length).min(self.total_length);
+
+        if piece as usize >= self.piece_count || end > piece_end {
+            return Err(StorageError::OutOfRange {
+                piece,
+                begin,
+                length,
+            });
+        }
+
+        let first = self.files.partition_point(|f| f.offset + f.length <= start);
+
+        let chunks = self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, f)| f.offset < end)
+            .filter(|(_, f)| f.length > 0)
+            .map(|(i, f)| {
+                let from = start.max(f.offset);
+                let to = end.min(f.offset + f.length);
+
+                Chunk {
+                    file: first + i,
+                    file_offset: (from - f.offset) as u64,
+                    range: from - start..to - start,
+                }
+            })
+            .collect();
+
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::{DownloadTypes, MultipleFileInner};
+    use std::collections::BTreeMap;
+
+    fn info(files: &[(usize, &str)]) -> MetaInfo {
+        let files = files
+            .iter()
+            .map(|(length, path)| {
+                MultipleFileInner::new(*length, path.split('/').map(String::from).collect())
+            })
+            .collect::<Vec<MultipleFileInner>>();
+
+        let total = files.iter().map(|f| f.length()).sum::<usize>();
+
+        MetaInfo {
+            name: "download".to_string(),
+            piece_length: 8,
+            pieces: vec![0; total.div_ceil(8) * 20],
+            files: DownloadTypes::Multiple { files },
+            leftovers: BTreeMap::new(),
+        }
+    }
+
+    fn temp_root() -> PathBuf {
+        std::env::temp_dir().join(format!("tc-storage-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn storage_chunks() {
+        let storage =
+            Storage::new(&info(&[(5, "a"), (0, "b"), (6, "c/d"), (4, "e")]), "x").unwrap();
+
+        // Piece 0 is bytes 0..8, straddling a, the empty b and c/d
+        assert_eq!(
+            storage.chunks(0, 2, 6).unwrap(),
+            vec![
+                Chunk {
+                    file: 0,
+                    file_offset: 2,
+                    range: 0..3,
+                },
+                Chunk {
+                    file: 2,
+                    file_offset: 0,
+                    range: 3..6,
+                },
+            ]
+        );
+
+        // The last piece is bytes 8..15, from the middle of c/d into e
+        assert_eq!(
+            storage.chunks(1, 0, 7).unwrap(),
+            vec![
+                Chunk {
+                    file: 2,
+                    file_offset: 3,
+                    range: 0..3,
+                },
+                Chunk {
+                    file: 3,
+                    file_offset: 0,
+                    range: 3..7,
+                },
+            ]
+        );
+
+        assert!(matches!(
+            storage.chunks(1, 0, 8),
+            Err(StorageError::OutOfRange { piece: 1, .. })
+        ));
+        assert!(storage.chunks(0, 4, 5).is_err());
+        assert!(storage.chunks(2, 0, 1).is_err());
+
+        assert_eq!(
+            storage.path(2).unwrap(),
+            Path::new("x").join("download").join("c").join("d")
+        );
+    }
+
+    #[test]
+    fn storage_read_write() {
+        let root = temp_root();
+        let storage =
+            Storage::new(&info(&[(5, "a"), (0, "b"), (6, "c/d"), (4, "e")]), &root).unwrap();
+
+        let data = (0..15).collect::<Vec<u8>>();
+        storage.write(1, 0, &data[8..]).unwrap();
+
+        // Nothing at the start of the first file yet
+        assert!(matches!(storage.read(0, 0, 8), Err(StorageError::Io(_))));
+
+        storage.write(0, 0, &data[..4]).unwrap();
+        storage.write(0, 4, &data[4..8]).unwrap();
+
+        assert_eq!(storage.read(0, 0, 8).unwrap(), &data[..8]);
+        assert_eq!(storage.read(0, 3, 5).unwrap(), &data[3..8]);
+        assert_eq!(storage.read(1, 0, 7).unwrap(), &data[8..]);
+
+        // Files stay open, the empty one was never needed
+        let open = storage.handles.iter().map(|h| h.lock().unwrap().is_some());
+        assert_eq!(open.collect::<Vec<_>>(), vec![true, false, true, true]);
+
+        // The concatenation of the files is the download
+        let on_disk = (0..4)
+            .flat_map(|i| std::fs::read(storage.path(i).unwrap()).unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(on_disk, data);
+
+        storage.allocate().unwrap();
+        assert!(storage.path(1).unwrap().exists());
+        assert_eq!(storage.read(0, 0, 8).unwrap(), &data[..8]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn storage_single_file() {
+        let root = temp_root();
+        let mut info = info(&[]);
+        info.files = DownloadTypes::Single { length: 10 };
+        info.pieces = vec![0; 40];
+
+        let storage = Storage::new(&info, &root).unwrap();
+        storage.allocate().unwrap();
+
+        assert_eq!(std::fs::metadata(root.join("download")).unwrap().len(), 10);
+
+        storage.write(1, 1, &[7]).unwrap();
+        assert_eq!(storage.read(1, 0, 2).unwrap(), vec![0, 7]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn storage_bad_paths() {
+        for path in ["../evil", "a//b", "./a", "a/b\\..\\..\\c"] {
+            assert!(matches!(
+                Storage::new(&info(&[(1, path)]), "x"),
+                Err(StorageError::BadPath(_))
+            ));
+        }
+
+        let mut info = info(&[(1, "a")]);
+        info.name = "..".to_string();
+        assert!(Storage::new(&info, "x").is_err());
+    }
+}
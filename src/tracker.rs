@@ -1,22 +1,132 @@
-use crate::encoding::{errors::BencodingError, types::BTypes};
+pub mod announcer;
+pub mod errors;
+pub mod http;
+pub mod mock;
+pub mod multi;
+pub mod udp;
+
 use crate::metainfo::*;
-use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+use errors::TrackerError;
+use http::HttpTracker;
 use rand::{self, Rng};
-use reqwest::get;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use udp::UdpTracker;
+
+pub type TrackerResult<T> = Result<T, TrackerError>;
+
+/// Size of a single `peers` entry in a compact peer list, 4 address bytes followed by a 2 byte port.
+pub const COMPACT_PEER_LEN: usize = 6;
+
+/// Size of a single `peers6` entry in a compact peer list, 16 address bytes followed by a 2 byte port.
+pub const COMPACT_PEER6_LEN: usize = 18;
+
+/// Common interface over the tracker protocols.
+pub trait Tracker {
+    /// Reports our state for a torrent and asks for peers.
+    fn announce(
+        &mut self,
+        details: &TrackerDetails<'_>,
+    ) -> impl Future<Output = TrackerResult<TrackerResponse>> + Send;
+
+    /// Asks for swarm statistics, returned in the same order as `info_hashes`.
+    fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> impl Future<Output = TrackerResult<Vec<ScrapeStats>>> + Send;
+}
+
+/// Tracker backend picked from the scheme of an announce URL.
+pub enum AnyTracker {
+    Http(HttpTracker),
+    Udp(UdpTracker),
+}
+
+impl AnyTracker {
+    pub async fn from_url(url: &str) -> TrackerResult<Self> {
+        if url.starts_with("udp://") {
+            return Ok(Self::Udp(UdpTracker::connect(url).await?));
+        }
+
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Ok(Self::Http(HttpTracker::new(url)));
+        }
+
+        Err(TrackerError::UnsupportedUrl(url.to_owned()))
+    }
+}
+
+impl Tracker for AnyTracker {
+    async fn announce(&mut self, details: &TrackerDetails<'_>) -> TrackerResult<TrackerResponse> {
+        match self {
+            Self::Http(t) => t.announce(details).await,
+            Self::Udp(t) => t.announce(details).await,
+        }
+    }
 
-// Refactor this into a trait for different tracker protocols
+    async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> TrackerResult<Vec<ScrapeStats>> {
+        match self {
+            Self::Http(t) => t.scrape(info_hashes).await,
+            Self::Udp(t) => t.scrape(info_hashes).await,
+        }
+    }
+}
 
+/// Parameters of a single announce.
 pub struct TrackerDetails<'a> {
-    meta: &'a Meta,
-    peer_id: String,
-    port: usize,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
-    event: TrackerEvent,
+    pub meta: &'a Meta,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    pub event: TrackerEvent,
+
+    /// `tracker id` from a previous response, echoed back on later announces.
+    pub tracker_id: Option<String>,
+
+    pub options: AnnounceOptions,
 }
 
-#[derive(Clone, Copy)]
+/// Optional announce parameters, kept for the whole session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceOptions {
+    /// Random value that lets the tracker recognise us if our IP address changes.
+    /// Must stay the same across every announce of a session.
+    pub key: u32,
+
+    /// Number of peers wanted, `None` leaves it to the tracker's default.
+    pub numwant: Option<usize>,
+
+    /// Our address, for when the tracker cannot see it (e.g. both behind the same NAT).
+    pub ip: Option<IpAddr>,
+
+    /// Tells HTTP trackers to leave peer IDs out of dictionary model peer lists.
+    pub no_peer_id: bool,
+
+    /// Advertises support for encrypted connections.
+    pub supportcrypto: bool,
+}
+
+impl AnnounceOptions {
+    /// Options with a freshly generated session `key` and everything else left to the tracker.
+    pub fn new() -> Self {
+        Self {
+            key: rand::random(),
+            numwant: None,
+            ip: None,
+            no_peer_id: false,
+            supportcrypto: false,
+        }
+    }
+}
+
+impl Default for AnnounceOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TrackerEvent {
     Started,
     Completed,
@@ -25,76 +135,150 @@ pub enum TrackerEvent {
 }
 
 impl TrackerEvent {
-    fn header_value(&self) -> &'static str {
+    /// `None` for regular announces, which leave the `event` parameter out.
+    fn header_value(&self) -> Option<&'static str> {
         match self {
-            Self::Started => "started",
-            Self::Completed => "completed",
-            Self::Stopped => "stopped",
-            Self::Empty => "empty",
+            Self::Started => Some("started"),
+            Self::Completed => Some("completed"),
+            Self::Stopped => Some("stopped"),
+            Self::Empty => None,
         }
     }
 }
 
-pub async fn tracker_get(
-    // IP not here rn come back later
-    meta: &Meta,
-    port: u16,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
-    event: TrackerEvent,
-) -> [u8; 20] {
-    let info_hash = percent_encode(meta.info_hash().as_slice(), NON_ALPHANUMERIC).to_string();
-
-    let peer_id_bytes = generate_peer_id();
-    // Generate random ID and return with response, shouldn't need to be percent encoded with current generation method
-    // let peer_id = percent_encode(peer_id_bytes.as_slice(), NON_ALPHANUMERIC).to_string();
-    let peer_id = "abcdefghijklmnopqrst";
-
-    println!("Peer id: {:?}", &peer_id);
-    println!("Peer id bytes: {:?}", &peer_id_bytes);
-
-    let query_string = format!(
-        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&event={}",
-        info_hash,
-        peer_id,
-        port,
-        uploaded,
-        downloaded,
-        left,
-        event.header_value()
-    );
-
-    let response = match reqwest::Client::new()
-        .get(format!("{}?{}", meta.announce, query_string))
-        .send()
-        .await
-    {
-        Ok(r) => r,
-        Err(e) => panic!("{:?}", e),
-    };
+/// Decoded body of a successful announce.
+/// Optional keys are `None` when the tracker left them out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerResponse {
+    /// Number of seconds the client should wait between regular re-announces.
+    pub interval: usize,
+
+    /// Clients must not re-announce more frequently than this, in seconds.
+    pub min_interval: Option<usize>,
+
+    /// A string the client should send back on its next announcements.
+    pub tracker_id: Option<String>,
 
-    println!("{:?}", response);
-    println!("{:?}", response.text().await);
+    /// Number of peers with the entire file, i.e. seeders.
+    pub complete: Option<usize>,
 
-    peer_id_bytes
+    /// Number of non-seeder peers, aka "leechers".
+    pub incomplete: Option<usize>,
+
+    /// Similar to a failure reason, but the response still gets processed normally.
+    pub warning_message: Option<String>,
+
+    /// Peers handed out by the tracker.
+    pub peers: Vec<TrackerPeer>,
+}
+
+/// Swarm statistics for a single torrent, as returned by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Number of peers with the entire file, i.e. seeders.
+    pub complete: usize,
+
+    /// Total number of times the tracker has registered a completion.
+    pub downloaded: usize,
+
+    /// Number of non-seeder peers, aka "leechers".
+    pub incomplete: usize,
+}
+
+/// A peer handed out by the tracker, from either the dictionary or compact model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerPeer {
+    /// The peer's self-selected ID. Only the dictionary model carries one.
+    pub peer_id: Option<Vec<u8>>,
+
+    pub addr: SocketAddr,
 }
 
 pub fn generate_peer_id() -> [u8; 20] {
-    let mut chars = ['A' as u8; 20];
+    let mut chars = [b'A'; 20];
     let mut rng = rand::rng();
 
-    for ch in 0..chars.len() {
-        chars[ch] = rng.sample(rand::distr::Alphanumeric) as u8;
+    for ch in chars.iter_mut() {
+        *ch = rng.sample(rand::distr::Alphanumeric);
     }
 
     chars
 }
 
-fn decode_response(response: String) -> Result<(), BencodingError> {
-    let res = BTypes::bdecode(&response.into_bytes())?;
+/// Splits a compact address string into `entry_len` sized address and port entries in network byte order.
+/// Returns `None` if `entry_len` is not one of the compact sizes or the input is not a multiple of it.
+pub fn decode_compact(compact: &[u8], entry_len: usize) -> Option<Vec<SocketAddr>> {
+    if !matches!(entry_len, COMPACT_PEER_LEN | COMPACT_PEER6_LEN)
+        || !compact.len().is_multiple_of(entry_len)
+    {
+        return None;
+    }
+
+    compact
+        .chunks_exact(entry_len)
+        .map(|entry| {
+            let (ip, port) = entry.split_at(entry_len - 2);
+            let port = u16::from_be_bytes([port[0], port[1]]);
+
+            let ip = match entry_len {
+                COMPACT_PEER_LEN => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?)),
+                _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)),
+            };
+
+            Some(SocketAddr::new(ip, port))
+        })
+        .collect()
+}
+
+/// Inverse of `decode_compact` for a single peer,
+/// yields a `COMPACT_PEER_LEN` entry for IPv4 and a `COMPACT_PEER6_LEN` one for IPv6.
+pub fn encode_compact(addr: &SocketAddr) -> Vec<u8> {
+    let mut entry = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    entry.extend(addr.port().to_be_bytes());
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let (peers, res) = res.keyed_dict("peers")?;
+    #[test]
+    fn decode_compact_1() {
+        const SAMPLE: &[u8] = &[127, 0, 0, 1, 0x1a, 0xe1];
 
-    Ok(())
+        assert_eq!(
+            decode_compact(SAMPLE, COMPACT_PEER_LEN),
+            Some(vec!["127.0.0.1:6881".parse().unwrap()])
+        );
+        assert_eq!(decode_compact(SAMPLE, COMPACT_PEER6_LEN), None);
+        assert_eq!(decode_compact(SAMPLE, 0), None);
+        assert_eq!(decode_compact(&[], COMPACT_PEER_LEN), Some(vec![]));
+
+        assert_eq!(encode_compact(&"127.0.0.1:6881".parse().unwrap()), SAMPLE);
+        assert_eq!(
+            encode_compact(&"[::1]:6881".parse().unwrap()).len(),
+            COMPACT_PEER6_LEN
+        );
+    }
+
+    #[tokio::test]
+    async fn any_tracker_from_url() {
+        assert!(matches!(
+            AnyTracker::from_url("http://example.com/announce").await,
+            Ok(AnyTracker::Http(_))
+        ));
+        assert!(matches!(
+            AnyTracker::from_url("udp://127.0.0.1:6969/announce").await,
+            Ok(AnyTracker::Udp(_))
+        ));
+        assert!(
+            AnyTracker::from_url("wss://example.com/announce")
+                .await
+                .is_err()
+        );
+    }
 }
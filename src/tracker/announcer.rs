@@ -0,0 +1,573 @@
+use super::{
+    AnnounceOptions, Tracker, TrackerDetails, TrackerEvent, TrackerResponse, TrackerResult,
+};
+use crate::metainfo::Meta;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep_until};
+
+/// First retry delay after a failed announce, doubled on each consecutive failure.
+const BACKOFF_BASE: Duration = Duration::from_secs(15);
+
+/// Retry delay stops doubling here.
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Buffered commands and responses before senders wait.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// The final `stopped` announce is abandoned after this, so shutting down never hangs on a dead tracker.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transfer counters reported in each announce.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+}
+
+enum AnnounceCommand {
+    Completed,
+    Stop,
+}
+
+/// Controls the announce task of a single torrent.
+pub struct AnnounceHandle {
+    commands: mpsc::Sender<AnnounceCommand>,
+    stats: watch::Sender<TransferStats>,
+    task: JoinHandle<()>,
+}
+
+impl AnnounceHandle {
+    /// Updates the counters sent with the next announce, only the latest ones are kept.
+    pub fn progress(&self, stats: TransferStats) {
+        self.stats.send_replace(stats);
+    }
+
+    /// Reports the download as finished, the tracker hears `completed` at most once.
+    pub async fn completed(&self) {
+        let _ = self.commands.send(AnnounceCommand::Completed).await;
+    }
+
+    /// Sends a final `stopped` announce and waits for the task to exit.
+    /// An announce still waiting on the tracker is abandoned.
+    pub async fn stop(self) {
+        let _ = self.commands.send(AnnounceCommand::Stop).await;
+        let _ = self.task.await;
+    }
+}
+
+/// Spawns the announce task of a torrent.
+/// Announces `started` immediately, then re-announces every `interval` (never faster than `min interval`),
+/// echoing the tracker id, and backs off exponentially while the tracker is failing.
+/// `options` are sent unchanged with every announce, so its `key` stays stable for the session.
+/// The outcome of every announce except the final `stopped` one is forwarded on the returned receiver.
+pub fn spawn_announcer<T: Tracker + Send + 'static>(
+    tracker: T,
+    meta: Arc<Meta>,
+    peer_id: [u8; 20],
+    port: u16,
+    stats: TransferStats,
+    options: AnnounceOptions,
+) -> (
+    AnnounceHandle,
+    mpsc::Receiver<TrackerResult<TrackerResponse>>,
+) {
+    let (command_tx, command_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let (response_tx, response_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let (stats_tx, stats_rx) = watch::channel(stats);
+
+    let announcer = Announcer {
+        meta,
+        peer_id,
+        port,
+        stats: stats_rx,
+        options,
+        tracker_id: None,
+        event: TrackerEvent::Started,
+        completed_requested: false,
+        completed_sent: false,
+        failures: 0,
+        min_interval: Duration::ZERO,
+        last_announce: None,
+        next: Instant::now(),
+    };
+
+    let task = tokio::spawn(announcer.run(tracker, command_rx, response_tx));
+
+    (
+        AnnounceHandle {
+            commands: command_tx,
+            stats: stats_tx,
+            task,
+        },
+        response_rx,
+    )
+}
+
+struct Announcer {
+    meta: Arc<Meta>,
+    peer_id: [u8; 20],
+    port: u16,
+    stats: watch::Receiver<TransferStats>,
+    options: AnnounceOptions,
+    tracker_id: Option<String>,
+
+    /// Event of the next announce, stays put until an announce carrying it succeeds.
+    event: TrackerEvent,
+
+    /// `AnnounceHandle::completed` was called, a torrent that was complete from the start never announces `completed`.
+    completed_requested: bool,
+    completed_sent: bool,
+    failures: u32,
+    min_interval: Duration,
+    last_announce: Option<Instant>,
+
+    /// When the next announce is due.
+    next: Instant,
+}
+
+impl Announcer {
+    async fn run<T: Tracker>(
+        mut self,
+        mut tracker: T,
+        mut commands: mpsc::Receiver<AnnounceCommand>,
+        responses: mpsc::Sender<TrackerResult<TrackerResponse>>,
+    ) {
+        let meta = self.meta.clone();
+
+        'run: loop {
+            tokio::select! {
+                _ = sleep_until(self.next) => {}
+                command = commands.recv() => {
+                    if !self.command(command) {
+                        break;
+                    }
+
+                    continue;
+                }
+            }
+
+            let details = self.details(&meta);
+            let request = tracker.announce(&details);
+            tokio::pin!(request);
+
+            // Commands are still taken while the tracker answers, `stop` abandons the announce
+            let result = loop {
+                tokio::select! {
+                    result = &mut request => break result,
+                    command = commands.recv() => {
+                        if !self.command(command) {
+                            break 'run;
+                        }
+                    }
+                }
+            };
+
+            let outcome = self.response(details.event, result);
+
+            self.next = match outcome {
+                Ok((response, wait)) => {
+                    let _ = responses.send(Ok(response)).await;
+                    Instant::now() + wait
+                }
+                Err(e) => {
+                    let _ = responses.send(Err(e)).await;
+                    Instant::now() + self.backoff()
+                }
+            };
+        }
+
+        self.event = TrackerEvent::Stopped;
+        let details = self.details(&meta);
+        let _ = tokio::time::timeout(STOP_TIMEOUT, tracker.announce(&details)).await;
+    }
+
+    /// Returns whether the task keeps running.
+    fn command(&mut self, command: Option<AnnounceCommand>) -> bool {
+        match command {
+            Some(AnnounceCommand::Completed) => {
+                self.completed_requested = true;
+
+                if !self.completed_sent && self.event != TrackerEvent::Started {
+                    self.event = TrackerEvent::Completed;
+                    self.next = self.earliest_announce();
+                }
+
+                true
+            }
+            Some(AnnounceCommand::Stop) | None => false,
+        }
+    }
+
+    /// Parameters of the next announce, which counts as made from now on.
+    fn details<'a>(&mut self, meta: &'a Meta) -> TrackerDetails<'a> {
+        let stats = *self.stats.borrow();
+
+        self.last_announce = Some(Instant::now());
+
+        TrackerDetails {
+            meta,
+            peer_id: self.peer_id,
+            port: self.port,
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            left: if self.completed_requested {
+                0
+            } else {
+                stats.left
+            },
+            event: self.event,
+            tracker_id: self.tracker_id.clone(),
+            options: self.options.clone(),
+        }
+    }
+
+    /// Takes in the outcome of an announce that carried `sent`.
+    /// Returns the response and how long to wait before the next regular announce.
+    fn response(
+        &mut self,
+        sent: TrackerEvent,
+        result: TrackerResult<TrackerResponse>,
+    ) -> TrackerResult<(TrackerResponse, Duration)> {
+        let response = match result {
+            Ok(r) => r,
+            Err(e) => {
+                self.failures += 1;
+                return Err(e);
+            }
+        };
+
+        self.failures = 0;
+
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id.clone();
+        }
+
+        self.min_interval = Duration::from_secs(response.min_interval.unwrap_or(0) as u64);
+
+        // Finishing while `started` was still outstanding is reported as `completed` right after it,
+        // as is finishing while a regular announce was on its way
+        self.event = match sent {
+            TrackerEvent::Started if self.completed_requested && !self.completed_sent => {
+                TrackerEvent::Completed
+            }
+            TrackerEvent::Completed => {
+                self.completed_sent = true;
+                TrackerEvent::Empty
+            }
+            _ if self.event == TrackerEvent::Completed => TrackerEvent::Completed,
+            _ => TrackerEvent::Empty,
+        };
+
+        let wait = match self.event {
+            TrackerEvent::Completed => self.min_interval,
+            _ => Duration::from_secs(response.interval as u64).max(self.min_interval),
+        };
+
+        Ok((response, wait))
+    }
+
+    /// `BACKOFF_BASE * 2 ^ (failures - 1)`, capped at `BACKOFF_MAX`.
+    fn backoff(&self) -> Duration {
+        let exponent = self.failures.saturating_sub(1).min(16);
+
+        (BACKOFF_BASE * 2_u32.pow(exponent)).min(BACKOFF_MAX)
+    }
+
+    fn earliest_announce(&self) -> Instant {
+        match self.last_announce {
+            Some(last) => last + self.min_interval,
+            None => Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::sample_meta;
+    use crate::tracker::mock::MockTracker;
+
+    fn sample_response(interval: usize, min_interval: Option<usize>) -> TrackerResponse {
+        TrackerResponse {
+            interval,
+            min_interval,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers: vec![],
+        }
+    }
+
+    fn spawn(
+        tracker: &MockTracker,
+    ) -> (
+        AnnounceHandle,
+        mpsc::Receiver<TrackerResult<TrackerResponse>>,
+    ) {
+        let stats = TransferStats {
+            uploaded: 0,
+            downloaded: 0,
+            left: 4,
+        };
+
+        spawn_announcer(
+            tracker.clone(),
+            Arc::new(sample_meta()),
+            [b'a'; 20],
+            6881,
+            stats,
+            AnnounceOptions::new(),
+        )
+    }
+
+    fn events(tracker: &MockTracker) -> Vec<TrackerEvent> {
+        tracker.state().announces.iter().map(|a| a.event).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_interval() {
+        let tracker = MockTracker::new(sample_response(1800, None));
+        let (handle, mut responses) = spawn(&tracker);
+
+        let start = Instant::now();
+        responses.recv().await.unwrap().unwrap();
+        responses.recv().await.unwrap().unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1800));
+
+        handle.stop().await;
+
+        assert_eq!(
+            events(&tracker),
+            vec![
+                TrackerEvent::Started,
+                TrackerEvent::Empty,
+                TrackerEvent::Stopped
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_min_interval() {
+        let tracker = MockTracker::new(sample_response(10, Some(60)));
+        let (handle, mut responses) = spawn(&tracker);
+
+        let start = Instant::now();
+        responses.recv().await.unwrap().unwrap();
+        responses.recv().await.unwrap().unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+        // Completion waits out the minimum interval too
+        handle.completed().await;
+        responses.recv().await.unwrap().unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(120));
+
+        handle.stop().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_completed_once() {
+        let tracker = MockTracker::new(sample_response(1800, None));
+        let (handle, mut responses) = spawn(&tracker);
+
+        responses.recv().await.unwrap().unwrap();
+
+        handle.completed().await;
+        responses.recv().await.unwrap().unwrap();
+        handle.completed().await;
+        responses.recv().await.unwrap().unwrap();
+        handle.stop().await;
+
+        let events = events(&tracker);
+        assert_eq!(
+            events,
+            vec![
+                TrackerEvent::Started,
+                TrackerEvent::Completed,
+                TrackerEvent::Empty,
+                TrackerEvent::Stopped
+            ]
+        );
+        assert_eq!(tracker.state().announces[1].left, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_completed_before_started() {
+        let tracker = MockTracker::new(sample_response(1800, None));
+        tracker.state().failures = 1;
+        let (handle, mut responses) = spawn(&tracker);
+
+        handle.completed().await;
+        assert!(responses.recv().await.unwrap().is_err());
+        responses.recv().await.unwrap().unwrap();
+        responses.recv().await.unwrap().unwrap();
+        handle.stop().await;
+
+        assert_eq!(
+            events(&tracker),
+            vec![
+                TrackerEvent::Started,
+                TrackerEvent::Started,
+                TrackerEvent::Completed,
+                TrackerEvent::Stopped
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_complete_from_start() {
+        let tracker = MockTracker::new(sample_response(1800, None));
+        let (handle, mut responses) = spawn_announcer(
+            tracker.clone(),
+            Arc::new(sample_meta()),
+            [b'a'; 20],
+            6881,
+            TransferStats::default(),
+            AnnounceOptions::new(),
+        );
+
+        // Seeding from the start is never reported as `completed`
+        responses.recv().await.unwrap().unwrap();
+        responses.recv().await.unwrap().unwrap();
+        handle.stop().await;
+
+        assert_eq!(
+            events(&tracker),
+            vec![
+                TrackerEvent::Started,
+                TrackerEvent::Empty,
+                TrackerEvent::Stopped
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_stop_timeout() {
+        let tracker = MockTracker::new(sample_response(1800, None));
+        let (handle, mut responses) = spawn(&tracker);
+
+        responses.recv().await.unwrap().unwrap();
+        tracker.state().delay = Duration::from_secs(3600);
+
+        let start = Instant::now();
+        handle.stop().await;
+        assert_eq!(start.elapsed(), STOP_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_slow_tracker() {
+        let tracker = MockTracker::new(sample_response(60, None));
+        let (handle, mut responses) = spawn(&tracker);
+
+        responses.recv().await.unwrap().unwrap();
+        tracker.state().delay = Duration::from_secs(10);
+
+        // Finishing while a regular announce is on its way is reported right after it
+        tokio::time::advance(Duration::from_secs(60)).await;
+        while tracker.state().announces.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        handle.completed().await;
+        responses.recv().await.unwrap().unwrap();
+        responses.recv().await.unwrap().unwrap();
+
+        // Stopping abandons an announce the tracker never answers
+        tracker.state().delay = Duration::from_secs(3600);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        while tracker.state().announces.len() < 4 {
+            tokio::task::yield_now().await;
+        }
+
+        for uploaded in 0..100 {
+            handle.progress(TransferStats {
+                uploaded,
+                ..TransferStats::default()
+            });
+        }
+
+        tracker.state().delay = Duration::ZERO;
+        let start = Instant::now();
+        handle.stop().await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        assert_eq!(
+            events(&tracker),
+            vec![
+                TrackerEvent::Started,
+                TrackerEvent::Empty,
+                TrackerEvent::Completed,
+                TrackerEvent::Empty,
+                TrackerEvent::Stopped
+            ]
+        );
+        assert_eq!(tracker.state().announces[4].uploaded, 99);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_tracker_id() {
+        let mut response = sample_response(60, None);
+        response.tracker_id = Some("abc".to_owned());
+        let tracker = MockTracker::new(response);
+        let (handle, mut responses) = spawn(&tracker);
+
+        responses.recv().await.unwrap().unwrap();
+        tracker.state().response.tracker_id = None;
+        responses.recv().await.unwrap().unwrap();
+        responses.recv().await.unwrap().unwrap();
+        handle.stop().await;
+
+        let ids = tracker
+            .state()
+            .announces
+            .iter()
+            .map(|a| a.tracker_id.clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(ids[0], None);
+        assert!(ids[1..].iter().all(|id| id.as_deref() == Some("abc")));
+
+        // Same session key on every announce
+        let state = tracker.state();
+        let key = state.announces[0].options.key;
+        assert!(state.announces.iter().all(|a| a.options.key == key));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn announcer_backoff() {
+        let tracker = MockTracker::new(sample_response(1800, None));
+        tracker.state().failures = 3;
+        let (handle, mut responses) = spawn(&tracker);
+
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(responses.recv().await.unwrap().is_err());
+        }
+
+        responses.recv().await.unwrap().unwrap();
+
+        // 15 + 30 + 60 seconds of backoff
+        assert_eq!(start.elapsed(), Duration::from_secs(105));
+        assert_eq!(tracker.state().announces.len(), 4);
+
+        handle.progress(TransferStats {
+            uploaded: 1,
+            downloaded: 2,
+            left: 2,
+        });
+        handle.stop().await;
+
+        let state = tracker.state();
+        let stopped = state.announces.last().unwrap();
+        assert_eq!(stopped.event, TrackerEvent::Stopped);
+        assert_eq!(
+            (stopped.uploaded, stopped.downloaded, stopped.left),
+            (1, 2, 2)
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::encoding::errors::BencodingError;
+
+#[derive(Debug)]
+pub enum TrackerError {
+    /// HTTP request could not be sent or its body could not be read.
+    Http(reqwest::Error),
+    /// Socket level failure, including UDP trackers that never answer.
+    Io(std::io::Error),
+    /// HTTP tracker answered with a non-success status and no failure reason.
+    Status(u16),
+    /// Response body was not the bencoding we expected.
+    Decode(BencodingError),
+    /// Binary response did not match the protocol layout.
+    Malformed(&'static str),
+    /// Tracker rejected the request, carries its human readable reason.
+    Failure(String),
+    UnsupportedUrl(String),
+    ScrapeUnsupported(String),
+    NoTrackers,
+}
+
+impl std::fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackerError::Http(e) => write!(f, "Tracker request failed: {e}"),
+            TrackerError::Io(e) => write!(f, "Tracker socket error: {e}"),
+            TrackerError::Status(s) => write!(f, "Tracker responded with HTTP status {s}"),
+            TrackerError::Decode(e) => write!(f, "Could not decode tracker response: {e}"),
+            TrackerError::Malformed(m) => write!(f, "Malformed tracker response: {m}"),
+            TrackerError::Failure(r) => write!(f, "Tracker reported failure: {r}"),
+            TrackerError::UnsupportedUrl(u) => write!(f, "Unsupported tracker URL {u}"),
+            TrackerError::ScrapeUnsupported(u) => write!(f, "Tracker {u} does not support scrape"),
+            TrackerError::NoTrackers => write!(f, "Announce list has no trackers"),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TrackerError::Http(e) => Some(e),
+            TrackerError::Io(e) => Some(e),
+            TrackerError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(value: reqwest::Error) -> Self {
+        TrackerError::Http(value)
+    }
+}
+
+impl From<std::io::Error> for TrackerError {
+    fn from(value: std::io::Error) -> Self {
+        TrackerError::Io(value)
+    }
+}
+
+impl From<BencodingError> for TrackerError {
+    fn from(value: BencodingError) -> Self {
+        TrackerError::Decode(value)
+    }
+}
@@ -0,0 +1,576 @@
+use super::errors::TrackerError;
+use super::{COMPACT_PEER_LEN, COMPACT_PEER6_LEN, ScrapeStats, Tracker, TrackerDetails};
+use super::{TrackerPeer, TrackerResponse, TrackerResult, decode_compact};
+use crate::encoding::{errors::BencodingError, types::BTypes};
+use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+use std::net::{IpAddr, SocketAddr};
+
+/// Client side of the HTTP(S) tracker protocol.
+pub struct HttpTracker {
+    announce: String,
+    client: reqwest::Client,
+}
+
+impl HttpTracker {
+    pub fn new(announce: &str) -> Self {
+        Self {
+            announce: announce.to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Tracker for HttpTracker {
+    async fn announce(&mut self, details: &TrackerDetails<'_>) -> TrackerResult<TrackerResponse> {
+        let response = self
+            .client
+            .get(format!("{}?{}", self.announce, announce_query(details)))
+            .send()
+            .await?;
+
+        decode_response(response_body(response).await?)
+    }
+
+    async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> TrackerResult<Vec<ScrapeStats>> {
+        let Some(url) = scrape_url(&self.announce) else {
+            return Err(TrackerError::ScrapeUnsupported(self.announce.clone()));
+        };
+
+        let query_string = info_hashes
+            .iter()
+            .map(|h| format!("info_hash={}", percent_encode(h, NON_ALPHANUMERIC)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let separator = if url.contains('?') { '&' } else { '?' };
+
+        let response = self
+            .client
+            .get(format!("{url}{separator}{query_string}"))
+            .send()
+            .await?;
+
+        decode_scrape(response_body(response).await?, info_hashes)
+    }
+}
+
+/// Query string of an announce, binary values percent-encoded byte by byte.
+fn announce_query(details: &TrackerDetails<'_>) -> String {
+    let info_hash = details.meta.info_hash();
+    let info_hash = percent_encode(&info_hash, NON_ALPHANUMERIC);
+    let peer_id = percent_encode(&details.peer_id, NON_ALPHANUMERIC);
+
+    let mut query_string = format!(
+        "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&key={:08x}",
+        info_hash,
+        peer_id,
+        details.port,
+        details.uploaded,
+        details.downloaded,
+        details.left,
+        details.options.key
+    );
+
+    if let Some(event) = details.event.header_value() {
+        query_string.push_str("&event=");
+        query_string.push_str(event);
+    }
+
+    if let Some(numwant) = details.options.numwant {
+        query_string.push_str(&format!("&numwant={numwant}"));
+    }
+
+    if let Some(ip) = details.options.ip {
+        query_string.push_str(&format!("&ip={ip}"));
+    }
+
+    if details.options.no_peer_id {
+        query_string.push_str("&no_peer_id=1");
+    }
+
+    if details.options.supportcrypto {
+        query_string.push_str("&supportcrypto=1");
+    }
+
+    if let Some(tracker_id) = &details.tracker_id {
+        query_string.push_str("&trackerid=");
+        query_string.extend(percent_encode(tracker_id.as_bytes(), NON_ALPHANUMERIC));
+    }
+
+    query_string
+}
+
+/// Reads the body of a response, surfacing a non-success status as an error.
+/// Trackers that reject a request with an error status may still explain why in a bencoded `failure reason`.
+async fn response_body(response: reqwest::Response) -> TrackerResult<Vec<u8>> {
+    let status = response.status();
+    let body = response.bytes().await?.to_vec();
+
+    if status.is_success() {
+        return Ok(body);
+    }
+
+    match failure_reason(&body) {
+        Some(reason) => Err(TrackerError::Failure(reason)),
+        None => Err(TrackerError::Status(status.as_u16())),
+    }
+}
+
+fn failure_reason(body: &Vec<u8>) -> Option<String> {
+    let (reason, _) = BTypes::bdecode(body)
+        .ok()?
+        .keyed_text_str("failure reason")
+        .ok()?;
+
+    Some(reason)
+}
+
+/// Derives the scrape URL by replacing `announce` at the start of the last path segment with `scrape`.
+/// Trackers whose announce URL does not follow that convention do not support scrape.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (base, last) = announce.split_at(announce.rfind('/')? + 1);
+    let rest = last.strip_prefix("announce")?;
+
+    Some(format!("{base}scrape{rest}"))
+}
+
+/// Picks the stats of each of `info_hashes` out of the `files` dict, which is keyed by raw info hash.
+/// Torrents the tracker does not know about are left out of the response and scrape as all zero.
+fn decode_scrape(response: Vec<u8>, info_hashes: &[[u8; 20]]) -> TrackerResult<Vec<ScrapeStats>> {
+    let res = BTypes::bdecode(&response)?;
+
+    let (failure, res) = res.keyed_optional("failure reason", BTypes::keyed_text_str)?;
+
+    if let Some(reason) = failure {
+        return Err(TrackerError::Failure(reason));
+    }
+
+    let (mut files, _) = res.keyed_dict("files")?;
+
+    let mut stats = Vec::new();
+
+    for hash in info_hashes {
+        let Some(file) = files.remove(hash.as_slice()) else {
+            stats.push(ScrapeStats {
+                complete: 0,
+                downloaded: 0,
+                incomplete: 0,
+            });
+            continue;
+        };
+
+        let (complete, file) = file.keyed_int("complete")?;
+        let (downloaded, file) = file.keyed_int("downloaded")?;
+        let (incomplete, _) = file.keyed_int("incomplete")?;
+
+        stats.push(ScrapeStats {
+            complete: int_in_range(complete, "complete")?,
+            downloaded: int_in_range(downloaded, "downloaded")?,
+            incomplete: int_in_range(incomplete, "incomplete")?,
+        });
+    }
+
+    Ok(stats)
+}
+
+fn decode_response(response: Vec<u8>) -> TrackerResult<TrackerResponse> {
+    let res = BTypes::bdecode(&response)?;
+
+    // If present, no other keys are required
+    let (failure, res) = res.keyed_optional("failure reason", BTypes::keyed_text_str)?;
+
+    if let Some(reason) = failure {
+        return Err(TrackerError::Failure(reason));
+    }
+
+    let (interval, res) = res.keyed_int("interval")?;
+    let (min_interval, res) = res.keyed_optional("min interval", BTypes::keyed_int)?;
+    let (tracker_id, res) = res.keyed_optional("tracker id", BTypes::keyed_text_str)?;
+    let (complete, res) = res.keyed_optional("complete", BTypes::keyed_int)?;
+    let (incomplete, res) = res.keyed_optional("incomplete", BTypes::keyed_int)?;
+    let (warning_message, res) = res.keyed_optional("warning message", BTypes::keyed_text_str)?;
+
+    let mut res = res.expect_dict()?;
+
+    // An IPv6 only tracker may leave out `peers` entirely
+    let peers6 = res.remove("peers6".as_bytes());
+
+    let mut peers = match (res.remove("peers".as_bytes()), &peers6) {
+        (Some(BTypes::List(list)), _) => decode_dict_peers(list)?,
+        (Some(compact), _) => {
+            decode_compact_peers(&compact.expect_bytes()?, COMPACT_PEER_LEN, "peers")?
+        }
+        (None, Some(_)) => Vec::new(),
+        (None, None) => return Err(BencodingError::KeyNotFound("peers".to_owned()).into()),
+    };
+
+    if let Some(compact) = peers6 {
+        peers.extend(decode_compact_peers(
+            &compact.expect_bytes()?,
+            COMPACT_PEER6_LEN,
+            "peers6",
+        )?);
+    }
+
+    Ok(TrackerResponse {
+        interval: int_in_range(interval, "interval")?,
+        min_interval: min_interval
+            .map(|v| int_in_range(v, "min interval"))
+            .transpose()?,
+        tracker_id,
+        complete: complete.map(|v| int_in_range(v, "complete")).transpose()?,
+        incomplete: incomplete
+            .map(|v| int_in_range(v, "incomplete"))
+            .transpose()?,
+        warning_message,
+        peers,
+    })
+}
+
+/// Decodes the dictionary model, a list of dicts with `peer id`, `ip` and `port` keys.
+/// Entries whose `ip` is a DNS name rather than an address are skipped.
+fn decode_dict_peers(list: Vec<BTypes>) -> Result<Vec<TrackerPeer>, BencodingError> {
+    let mut peers = Vec::new();
+
+    for peer in list {
+        let (peer_id, peer) = peer.keyed_optional("peer id", BTypes::keyed_bytes)?;
+        let (ip, peer) = peer.keyed_text_str("ip")?;
+        let (port, _) = peer.keyed_int("port")?;
+
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            continue;
+        };
+
+        peers.push(TrackerPeer {
+            peer_id,
+            addr: SocketAddr::new(ip, int_in_range(port, "port")?),
+        });
+    }
+
+    Ok(peers)
+}
+
+/// Bencoded integers are signed, counts and ports must fit their unsigned type.
+fn int_in_range<T: TryFrom<isize>>(value: isize, key: &str) -> Result<T, BencodingError> {
+    T::try_from(value).map_err(|_| BencodingError::IntOutOfRange(key.to_owned()))
+}
+
+/// Decodes the compact model, `peers` (BEP 23) or `peers6` (BEP 7), `key` only names the value in errors.
+fn decode_compact_peers(
+    compact: &[u8],
+    entry_len: usize,
+    key: &str,
+) -> Result<Vec<TrackerPeer>, BencodingError> {
+    let Some(addrs) = decode_compact(compact, entry_len) else {
+        return Err(BencodingError::MalformedString(key.to_owned()));
+    };
+
+    Ok(addrs
+        .into_iter()
+        .map(|addr| TrackerPeer {
+            peer_id: None,
+            addr,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_response_1() {
+        const SAMPLE: &[u8] = b"d8:completei3e10:incompletei1e8:intervali1800e12:min intervali900e5:peersld2:ip9:127.0.0.17:peer id20:abcdefghijklmnopqrst4:porti6881eed2:ip3:::14:porti51413eeee";
+
+        let result = TrackerResponse {
+            interval: 1800,
+            min_interval: Some(900),
+            tracker_id: None,
+            complete: Some(3),
+            incomplete: Some(1),
+            warning_message: None,
+            peers: vec![
+                TrackerPeer {
+                    peer_id: Some(b"abcdefghijklmnopqrst".to_vec()),
+                    addr: "127.0.0.1:6881".parse().unwrap(),
+                },
+                TrackerPeer {
+                    peer_id: None,
+                    addr: "[::1]:51413".parse().unwrap(),
+                },
+            ],
+        };
+
+        assert_eq!(decode_response(SAMPLE.to_vec()).unwrap(), result);
+    }
+
+    #[test]
+    fn decode_response_2() {
+        const SAMPLE: &[u8] =
+            b"d8:intervali60e10:tracker id3:abc15:warning message7:careful5:peerslee";
+
+        let result = decode_response(SAMPLE.to_vec()).unwrap();
+
+        assert_eq!(result.interval, 60);
+        assert_eq!(result.tracker_id, Some("abc".to_owned()));
+        assert_eq!(result.warning_message, Some("careful".to_owned()));
+        assert!(result.peers.is_empty());
+    }
+
+    #[test]
+    fn decode_response_compact() {
+        let mut sample = b"d8:intervali1800e5:peers12:".to_vec();
+        sample.extend([127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 200, 0xc8, 0xd5]);
+        sample.push(b'e');
+
+        let result = decode_response(sample).unwrap();
+
+        assert_eq!(
+            result.peers,
+            vec![
+                TrackerPeer {
+                    peer_id: None,
+                    addr: "127.0.0.1:6881".parse().unwrap(),
+                },
+                TrackerPeer {
+                    peer_id: None,
+                    addr: "10.0.0.200:51413".parse().unwrap(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_response_compact_bad_length() {
+        let mut sample = b"d8:intervali1800e5:peers5:".to_vec();
+        sample.extend([127, 0, 0, 1, 0x1a]);
+        sample.push(b'e');
+
+        assert!(matches!(
+            decode_response(sample),
+            Err(TrackerError::Decode(BencodingError::MalformedString(k))) if k == "peers"
+        ));
+    }
+
+    #[test]
+    fn decode_response_peers6() {
+        let mut sample = b"d8:intervali1800e5:peers6:".to_vec();
+        sample.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        sample.extend(b"6:peers618:");
+        sample.extend([
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1,
+        ]);
+        sample.push(b'e');
+
+        let result = decode_response(sample).unwrap();
+
+        assert_eq!(
+            result
+                .peers
+                .iter()
+                .map(|p| p.addr)
+                .collect::<Vec<SocketAddr>>(),
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_response_peers6_only() {
+        let mut sample = b"d8:intervali1800e6:peers618:".to_vec();
+        sample.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]);
+        sample.push(b'e');
+
+        let result = decode_response(sample).unwrap();
+
+        assert_eq!(result.peers.len(), 1);
+        assert_eq!(result.peers[0].addr, "[::1]:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn decode_response_peers6_bad_length() {
+        let mut sample = b"d8:intervali1800e5:peersle6:peers66:".to_vec();
+        sample.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        sample.push(b'e');
+
+        assert!(matches!(
+            decode_response(sample),
+            Err(TrackerError::Decode(BencodingError::MalformedString(k))) if k == "peers6"
+        ));
+    }
+
+    #[test]
+    fn decode_response_dns_peer() {
+        const SAMPLE: &[u8] = b"d8:intervali60e5:peersld2:ip11:example.com4:porti1eeee";
+
+        assert!(decode_response(SAMPLE.to_vec()).unwrap().peers.is_empty());
+    }
+
+    #[test]
+    fn decode_response_out_of_range() {
+        const SAMPLES: [(&[u8], &str); 3] = [
+            (b"d8:intervali-1e5:peerslee", "interval"),
+            (b"d8:completei-3e8:intervali60e5:peerslee", "complete"),
+            (
+                b"d8:intervali60e5:peersld2:ip9:127.0.0.14:porti70000eeee",
+                "port",
+            ),
+        ];
+
+        for (sample, key) in SAMPLES {
+            assert!(matches!(
+                decode_response(sample.to_vec()),
+                Err(TrackerError::Decode(BencodingError::IntOutOfRange(k))) if k == key
+            ));
+        }
+    }
+
+    #[test]
+    fn scrape_url_1() {
+        const URLS: [(&str, Option<&str>); 6] = [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape"),
+            ),
+            (
+                "http://example.com/x/announce",
+                Some("http://example.com/x/scrape"),
+            ),
+            (
+                "http://example.com/announce.php",
+                Some("http://example.com/scrape.php"),
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                Some("http://example.com/scrape?x2%0644"),
+            ),
+            ("http://example.com/a", None),
+            ("http://example.com/x%064announce", None),
+        ];
+
+        for (announce, scrape) in URLS {
+            assert_eq!(scrape_url(announce).as_deref(), scrape);
+        }
+    }
+
+    #[test]
+    fn decode_scrape_1() {
+        let mut sample = b"d5:filesd20:".to_vec();
+        sample.extend([0xaa; 20]);
+        sample.extend(b"d8:completei5e10:downloadedi50e10:incompletei10ee20:");
+        sample.extend([0xbb; 20]);
+        sample.extend(b"d8:completei1e10:downloadedi2e10:incompletei3eeee");
+
+        let result = decode_scrape(sample, &[[0xbb; 20], [0xcc; 20], [0xaa; 20]]).unwrap();
+
+        assert_eq!(
+            result,
+            vec![
+                ScrapeStats {
+                    complete: 1,
+                    downloaded: 2,
+                    incomplete: 3,
+                },
+                ScrapeStats {
+                    complete: 0,
+                    downloaded: 0,
+                    incomplete: 0,
+                },
+                ScrapeStats {
+                    complete: 5,
+                    downloaded: 50,
+                    incomplete: 10,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_scrape_missing_files() {
+        assert!(matches!(
+            decode_scrape(b"d5:otheri1ee".to_vec(), &[[0; 20]]),
+            Err(TrackerError::Decode(BencodingError::KeyNotFound(k))) if k == "files"
+        ));
+    }
+
+    #[test]
+    fn decode_response_missing_interval() {
+        const SAMPLE: &[u8] = b"d5:peerslee";
+
+        assert!(matches!(
+            decode_response(SAMPLE.to_vec()),
+            Err(TrackerError::Decode(BencodingError::KeyNotFound(k))) if k == "interval"
+        ));
+    }
+
+    #[test]
+    fn decode_response_failure() {
+        const SAMPLE: &[u8] = b"d14:failure reason17:torrent not founde";
+
+        assert!(matches!(
+            decode_response(SAMPLE.to_vec()),
+            Err(TrackerError::Failure(r)) if r == "torrent not found"
+        ));
+        assert!(matches!(
+            decode_scrape(SAMPLE.to_vec(), &[[0; 20]]),
+            Err(TrackerError::Failure(r)) if r == "torrent not found"
+        ));
+        assert_eq!(
+            failure_reason(&SAMPLE.to_vec()),
+            Some("torrent not found".to_owned())
+        );
+        assert_eq!(failure_reason(&b"<html>".to_vec()), None);
+    }
+
+    #[test]
+    fn announce_query_1() {
+        use crate::metainfo::sample_meta;
+        use crate::tracker::{AnnounceOptions, TrackerEvent};
+
+        let meta = sample_meta();
+
+        let peer_id = *b"-TC0001-\x00\xff bcdefghij";
+        let mut details = TrackerDetails {
+            meta: &meta,
+            peer_id,
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: TrackerEvent::Empty,
+            tracker_id: None,
+            options: AnnounceOptions {
+                key: 0xbeef,
+                numwant: None,
+                ip: None,
+                no_peer_id: false,
+                supportcrypto: false,
+            },
+        };
+
+        let info_hash = percent_encode(&meta.info_hash(), NON_ALPHANUMERIC).to_string();
+
+        assert_eq!(
+            announce_query(&details),
+            format!(
+                "info_hash={info_hash}&peer_id=%2DTC0001%2D%00%FF%20bcdefghij&port=6881&uploaded=1&downloaded=2&left=3&compact=1&key=0000beef"
+            )
+        );
+
+        details.event = TrackerEvent::Started;
+        details.tracker_id = Some("a b".to_owned());
+        details.options = AnnounceOptions {
+            key: 1,
+            numwant: Some(50),
+            ip: Some("::1".parse().unwrap()),
+            no_peer_id: true,
+            supportcrypto: true,
+        };
+
+        assert!(announce_query(&details).ends_with(
+            "&key=00000001&event=started&numwant=50&ip=::1&no_peer_id=1&supportcrypto=1&trackerid=a%20b"
+        ));
+    }
+}
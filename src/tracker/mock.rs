@@ -0,0 +1,174 @@
+use super::errors::TrackerError;
+use super::{
+    AnnounceOptions, ScrapeStats, Tracker, TrackerDetails, TrackerEvent, TrackerResponse,
+    TrackerResult,
+};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// In-memory tracker for tests, no network involved.
+/// Clones share state, so a test can keep a handle while another task owns the tracker.
+#[derive(Clone)]
+pub struct MockTracker {
+    state: Arc<Mutex<MockState>>,
+}
+
+pub struct MockState {
+    /// Returned from every successful announce.
+    pub response: TrackerResponse,
+
+    /// Scrape statistics per info hash, unknown hashes scrape as all zero.
+    pub scrapes: BTreeMap<[u8; 20], ScrapeStats>,
+
+    /// Number of upcoming announces that fail before the tracker recovers.
+    pub failures: usize,
+
+    /// Every announce received, oldest first.
+    pub announces: Vec<MockAnnounce>,
+
+    /// How long each announce takes to answer.
+    pub delay: Duration,
+}
+
+/// Owned copy of the `TrackerDetails` of an announce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockAnnounce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    pub event: TrackerEvent,
+    pub tracker_id: Option<String>,
+    pub options: AnnounceOptions,
+}
+
+impl MockTracker {
+    pub fn new(response: TrackerResponse) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                response,
+                scrapes: BTreeMap::new(),
+                failures: 0,
+                announces: Vec::new(),
+                delay: Duration::ZERO,
+            })),
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Tracker for MockTracker {
+    async fn announce(&mut self, details: &TrackerDetails<'_>) -> TrackerResult<TrackerResponse> {
+        let (result, delay) = {
+            let mut state = self.state();
+
+            state.announces.push(MockAnnounce {
+                info_hash: details.meta.info_hash(),
+                peer_id: details.peer_id,
+                port: details.port,
+                uploaded: details.uploaded,
+                downloaded: details.downloaded,
+                left: details.left,
+                event: details.event,
+                tracker_id: details.tracker_id.clone(),
+                options: details.options.clone(),
+            });
+
+            let result = if state.failures > 0 {
+                state.failures -= 1;
+                Err(TrackerError::Failure("Mock tracker failure".to_owned()))
+            } else {
+                Ok(state.response.clone())
+            };
+
+            (result, state.delay)
+        };
+
+        tokio::time::sleep(delay).await;
+
+        result
+    }
+
+    async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> TrackerResult<Vec<ScrapeStats>> {
+        let state = self.state();
+
+        Ok(info_hashes
+            .iter()
+            .map(|h| {
+                state.scrapes.get(h).copied().unwrap_or(ScrapeStats {
+                    complete: 0,
+                    downloaded: 0,
+                    incomplete: 0,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::sample_meta;
+
+    fn sample_response() -> TrackerResponse {
+        TrackerResponse {
+            interval: 1800,
+            min_interval: None,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_announce_1() {
+        let meta = sample_meta();
+        let mut tracker = MockTracker::new(sample_response());
+        let handle = tracker.clone();
+        handle.state().failures = 1;
+
+        let details = TrackerDetails {
+            meta: &meta,
+            peer_id: [b'a'; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 4,
+            event: TrackerEvent::Started,
+            tracker_id: None,
+            options: AnnounceOptions::new(),
+        };
+
+        assert!(tracker.announce(&details).await.is_err());
+        assert_eq!(tracker.announce(&details).await.unwrap(), sample_response());
+
+        let state = handle.state();
+        assert_eq!(state.announces.len(), 2);
+        assert_eq!(state.announces[1].info_hash, meta.info_hash());
+        assert_eq!(state.announces[1].event, TrackerEvent::Started);
+    }
+
+    #[tokio::test]
+    async fn mock_scrape_1() {
+        let mut tracker = MockTracker::new(sample_response());
+        let stats = ScrapeStats {
+            complete: 3,
+            downloaded: 10,
+            incomplete: 2,
+        };
+        tracker.state().scrapes.insert([1; 20], stats);
+
+        let result = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+
+        assert_eq!(result[0], stats);
+        assert_eq!(result[1].complete, 0);
+    }
+}
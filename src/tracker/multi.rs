@@ -0,0 +1,265 @@
+use super::errors::TrackerError;
+use super::{AnyTracker, ScrapeStats, Tracker, TrackerDetails, TrackerResponse, TrackerResult};
+use crate::metainfo::{AnnounceList, Meta};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::time::Duration;
+
+/// Time a single tracker gets before the next one is tried
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Creates the backend for a tracker URL the first time it is used.
+pub type Connector<T> =
+    Box<dyn Fn(&str) -> Pin<Box<dyn Future<Output = TrackerResult<T>> + Send>> + Send + Sync>;
+
+/// Announces to the tiers of an `AnnounceList` (BEP 12).
+/// Trackers are shuffled within their tier once, a tracker that responds is promoted to the front of its tier,
+/// and a tier is only abandoned for the next once every tracker in it has failed.
+pub struct MultiTracker<T> {
+    list: AnnounceList,
+    trackers: BTreeMap<String, T>,
+    connect: Connector<T>,
+
+    /// Deadline for one tracker to connect and answer, so a dead tracker cannot hold up the rest.
+    pub timeout: Duration,
+}
+
+impl MultiTracker<AnyTracker> {
+    pub fn from_meta(meta: &Meta) -> Self {
+        Self::new(
+            meta.trackers(),
+            Box::new(|url| {
+                let url = url.to_owned();
+                Box::pin(async move { AnyTracker::from_url(&url).await })
+            }),
+        )
+    }
+}
+
+impl<T: Tracker + Send> MultiTracker<T> {
+    pub fn new(mut list: AnnounceList, connect: Connector<T>) -> Self {
+        list.shuffle();
+
+        Self {
+            list,
+            trackers: BTreeMap::new(),
+            connect,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Current tracker order, reflecting promotions.
+    pub fn list(&self) -> &AnnounceList {
+        &self.list
+    }
+
+    async fn tracker(&mut self, url: &str) -> TrackerResult<&mut T> {
+        if !self.trackers.contains_key(url) {
+            let tracker = (self.connect)(url).await?;
+            self.trackers.insert(url.to_owned(), tracker);
+        }
+
+        Ok(self.trackers.get_mut(url).unwrap())
+    }
+}
+
+impl<T: Tracker + Send> Tracker for MultiTracker<T> {
+    async fn announce(&mut self, details: &TrackerDetails<'_>) -> TrackerResult<TrackerResponse> {
+        let mut last_error = None;
+
+        for tier in 0..self.list.tiers.len() {
+            for index in 0..self.list.tiers[tier].len() {
+                let url = self.list.tiers[tier][index].clone();
+                let timeout = self.timeout;
+
+                let result = deadline(timeout, async {
+                    self.tracker(&url).await?.announce(details).await
+                })
+                .await;
+
+                match result {
+                    Ok(response) => {
+                        self.list.promote(tier, index);
+                        return Ok(response);
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or(TrackerError::NoTrackers))
+    }
+
+    async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> TrackerResult<Vec<ScrapeStats>> {
+        let mut last_error = None;
+
+        for url in self.list.tiers.concat() {
+            let timeout = self.timeout;
+
+            let result = deadline(timeout, async {
+                self.tracker(&url).await?.scrape(info_hashes).await
+            })
+            .await;
+
+            match result {
+                Ok(stats) => return Ok(stats),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or(TrackerError::NoTrackers))
+    }
+}
+
+/// Runs one tracker request, running out of time is reported like an unanswered UDP tracker.
+async fn deadline<R>(
+    timeout: Duration,
+    request: impl Future<Output = TrackerResult<R>>,
+) -> TrackerResult<R> {
+    match tokio::time::timeout(timeout, request).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "Tracker did not respond in time").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::sample_meta;
+    use crate::tracker::mock::MockTracker;
+    use crate::tracker::{AnnounceOptions, TrackerEvent};
+    use std::sync::Arc;
+
+    fn sample_response(interval: usize) -> TrackerResponse {
+        TrackerResponse {
+            interval,
+            min_interval: None,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            peers: vec![],
+        }
+    }
+
+    /// One mock per URL, named by the interval they answer with.
+    /// `down` trackers fail every announce, `hang` never connects.
+    fn mock_connector(
+        down: &[&str],
+    ) -> (Connector<MockTracker>, Arc<BTreeMap<String, MockTracker>>) {
+        let mut mocks = BTreeMap::new();
+
+        for (i, url) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            let mock = MockTracker::new(sample_response(i));
+
+            if down.contains(&url) {
+                mock.state().failures = usize::MAX;
+            }
+
+            mocks.insert(url.to_owned(), mock);
+        }
+
+        let mocks = Arc::new(mocks);
+        let shared = mocks.clone();
+
+        let connect: Connector<MockTracker> = Box::new(move |url| {
+            let mock = shared.get(url).cloned();
+            let hang = url == "hang";
+
+            Box::pin(async move {
+                if hang {
+                    std::future::pending::<()>().await;
+                }
+
+                mock.ok_or(TrackerError::UnsupportedUrl("missing".to_owned()))
+            })
+        });
+
+        (connect, mocks)
+    }
+
+    fn details(meta: &Meta) -> TrackerDetails<'_> {
+        TrackerDetails {
+            meta,
+            peer_id: [b'a'; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 4,
+            event: TrackerEvent::Started,
+            tracker_id: None,
+            options: AnnounceOptions::new(),
+        }
+    }
+
+    fn tiers(tiers: &[&[&str]]) -> AnnounceList {
+        AnnounceList {
+            tiers: tiers
+                .iter()
+                .map(|t| t.iter().map(|u| u.to_string()).collect())
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn multi_promotes_within_tier() {
+        let meta = sample_meta();
+        let (connect, mocks) = mock_connector(&["a", "b"]);
+        let mut tracker = MultiTracker::new(tiers(&[&["a", "b", "c"]]), connect);
+
+        let response = tracker.announce(&details(&meta)).await.unwrap();
+
+        assert_eq!(response.interval, 2);
+        assert_eq!(tracker.list().tiers[0][0], "c");
+
+        // Promoted tracker is tried first from now on
+        tracker.announce(&details(&meta)).await.unwrap();
+        assert_eq!(mocks["c"].state().announces.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn multi_falls_back_to_next_tier() {
+        let meta = sample_meta();
+        let (connect, mocks) = mock_connector(&["a", "b"]);
+        let mut tracker = MultiTracker::new(tiers(&[&["a", "b"], &["d"]]), connect);
+
+        let response = tracker.announce(&details(&meta)).await.unwrap();
+
+        assert_eq!(response.interval, 3);
+        assert_eq!(mocks["a"].state().announces.len(), 1);
+        assert_eq!(mocks["b"].state().announces.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn multi_times_out() {
+        let meta = sample_meta();
+        let (connect, _) = mock_connector(&[]);
+        let mut tracker = MultiTracker::new(tiers(&[&["hang"], &["c"]]), connect);
+
+        let started = tokio::time::Instant::now();
+        let response = tracker.announce(&details(&meta)).await.unwrap();
+
+        assert_eq!(response.interval, 2);
+        assert_eq!(started.elapsed(), DEFAULT_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn multi_all_down() {
+        let meta = sample_meta();
+        let (connect, _) = mock_connector(&["a", "b"]);
+        let mut tracker = MultiTracker::new(tiers(&[&["a"], &["b", "missing"]]), connect);
+
+        // Last tracker tried decides the error
+        assert!(matches!(
+            tracker.announce(&details(&meta)).await,
+            Err(TrackerError::UnsupportedUrl(_) | TrackerError::Failure(_))
+        ));
+        assert!(matches!(
+            MultiTracker::new(tiers(&[]), mock_connector(&[]).0)
+                .announce(&details(&meta))
+                .await,
+            Err(TrackerError::NoTrackers)
+        ));
+    }
+}
@@ -0,0 +1,469 @@
+use super::errors::TrackerError;
+use super::{COMPACT_PEER_LEN, COMPACT_PEER6_LEN, ScrapeStats, Tracker, TrackerDetails};
+use super::{TrackerEvent, TrackerPeer, TrackerResponse, TrackerResult, decode_compact};
+use crate::encoding::read_u32;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::{UdpSocket, lookup_host};
+
+/// Magic constant identifying a connect request (BEP 15)
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A connection ID may be used for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Base of the `15 * 2 ^ n` retransmission timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// BEP 15 lets `n` grow to 8, over two hours for a dead tracker.
+/// Two retries give up after 105 s so announces can fall back to other trackers in time.
+const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// Largest datagram we expect back, enough for a few hundred peers
+const MAX_RESPONSE_LEN: usize = 8192;
+
+/// Scrapes are limited to about 74 torrents per request, larger scrapes are split
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Client side of the UDP tracker protocol (BEP 15).
+/// Holds a socket connected to a single tracker and caches the connection ID between requests.
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+
+    /// Base retransmission timeout, doubled after each attempt.
+    pub timeout: Duration,
+
+    /// Number of retransmissions before a request is abandoned.
+    pub max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolves a `udp://host:port/...` announce URL and connects a socket to it.
+    pub async fn connect(url: &str) -> std::io::Result<Self> {
+        let Some(rest) = url.strip_prefix("udp://") else {
+            return Err(Error::new(ErrorKind::InvalidInput, "Not a udp:// URL"));
+        };
+
+        let host = rest.split(['/', '?']).next().unwrap_or(rest);
+
+        let Some(addr) = lookup_host(host).await?.next() else {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Tracker host did not resolve",
+            ));
+        };
+
+        Self::connect_addr(addr).await
+    }
+
+    pub async fn connect_addr(addr: SocketAddr) -> std::io::Result<Self> {
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self {
+            socket,
+            connection: None,
+            timeout: DEFAULT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+        })
+    }
+
+    /// Scrapes up to `MAX_SCRAPE_HASHES` torrents in one request, stats are returned in the same order.
+    async fn scrape_chunk(&mut self, info_hashes: &[[u8; 20]]) -> TrackerResult<Vec<ScrapeStats>> {
+        let connection_id = self.connection_id().await?;
+        let transaction_id = rand::random::<u32>();
+
+        let mut request = Vec::with_capacity(16 + 20 * info_hashes.len());
+        request.extend(connection_id.to_be_bytes());
+        request.extend(ACTION_SCRAPE.to_be_bytes());
+        request.extend(transaction_id.to_be_bytes());
+        info_hashes.iter().for_each(|h| request.extend(h));
+
+        let min_len = 8 + 12 * info_hashes.len();
+
+        let response = self
+            .exchange(&request, ACTION_SCRAPE, transaction_id, min_len)
+            .await?;
+
+        Ok(response[8..min_len]
+            .chunks_exact(12)
+            .map(|entry| ScrapeStats {
+                complete: read_u32(entry, 0) as usize,
+                downloaded: read_u32(entry, 4) as usize,
+                incomplete: read_u32(entry, 8) as usize,
+            })
+            .collect())
+    }
+
+    /// Returns the cached connection ID, performing a connect exchange if it is missing or expired.
+    async fn connection_id(&mut self) -> TrackerResult<u64> {
+        if let Some((id, received)) = self.connection
+            && received.elapsed() < CONNECTION_ID_LIFETIME
+        {
+            return Ok(id);
+        }
+
+        let transaction_id = rand::random::<u32>();
+
+        let mut request = Vec::with_capacity(16);
+        request.extend(PROTOCOL_ID.to_be_bytes());
+        request.extend(ACTION_CONNECT.to_be_bytes());
+        request.extend(transaction_id.to_be_bytes());
+
+        let response = self
+            .exchange(&request, ACTION_CONNECT, transaction_id, 16)
+            .await?;
+
+        let id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+        self.connection = Some((id, Instant::now()));
+
+        Ok(id)
+    }
+
+    /// Sends `request` until a matching response arrives, waiting `timeout * 2 ^ n` before the n-th retransmission.
+    /// Responses with a foreign transaction ID are ignored, error responses are surfaced with their message.
+    async fn exchange(
+        &mut self,
+        request: &[u8],
+        action: u32,
+        transaction_id: u32,
+        min_len: usize,
+    ) -> TrackerResult<Vec<u8>> {
+        let mut buffer = vec![0; MAX_RESPONSE_LEN];
+
+        for attempt in 0..=self.max_retries {
+            self.socket.send(request).await?;
+
+            let deadline = tokio::time::Instant::now() + self.timeout * 2_u32.pow(attempt);
+
+            loop {
+                let received =
+                    match tokio::time::timeout_at(deadline, self.socket.recv(&mut buffer)).await {
+                        Ok(r) => r?,
+                        Err(_) => break,
+                    };
+
+                let response = &buffer[..received];
+
+                if response.len() < 8 || read_u32(response, 4) != transaction_id {
+                    continue;
+                }
+
+                match read_u32(response, 0) {
+                    ACTION_ERROR => {
+                        // The tracker may have expired our connection ID early
+                        self.connection = None;
+
+                        let message = String::from_utf8_lossy(&response[8..]).into_owned();
+                        return Err(TrackerError::Failure(message));
+                    }
+                    a if a == action && response.len() >= min_len => {
+                        return Ok(response.to_vec());
+                    }
+                    _ => {
+                        return Err(TrackerError::Malformed(
+                            "Unexpected action or truncated response",
+                        ));
+                    }
+                }
+            }
+        }
+
+        Err(Error::new(ErrorKind::TimedOut, "UDP tracker did not respond").into())
+    }
+}
+
+impl Tracker for UdpTracker {
+    async fn announce(&mut self, details: &TrackerDetails<'_>) -> TrackerResult<TrackerResponse> {
+        let connection_id = self.connection_id().await?;
+        let transaction_id = rand::random::<u32>();
+
+        let mut request = Vec::with_capacity(98);
+        request.extend(connection_id.to_be_bytes());
+        request.extend(ACTION_ANNOUNCE.to_be_bytes());
+        request.extend(transaction_id.to_be_bytes());
+        request.extend(details.meta.info_hash());
+        request.extend(details.peer_id);
+        request.extend((details.downloaded as u64).to_be_bytes());
+        request.extend((details.left as u64).to_be_bytes());
+        request.extend((details.uploaded as u64).to_be_bytes());
+        request.extend(details.event.udp_value().to_be_bytes());
+        request.extend(ip_value(details.options.ip).to_be_bytes());
+        request.extend(details.options.key.to_be_bytes());
+        request.extend(num_want_value(details.options.numwant).to_be_bytes());
+        request.extend(details.port.to_be_bytes());
+
+        let response = self
+            .exchange(&request, ACTION_ANNOUNCE, transaction_id, 20)
+            .await?;
+
+        // Peers come back in the address family the announce was sent over
+        let entry_len = match self.socket.peer_addr()? {
+            SocketAddr::V4(_) => COMPACT_PEER_LEN,
+            SocketAddr::V6(_) => COMPACT_PEER6_LEN,
+        };
+
+        let Some(addrs) = decode_compact(&response[20..], entry_len) else {
+            return Err(TrackerError::Malformed(
+                "Announce peer list has a partial entry",
+            ));
+        };
+
+        Ok(TrackerResponse {
+            interval: read_u32(&response, 8) as usize,
+            min_interval: None,
+            tracker_id: None,
+            complete: Some(read_u32(&response, 16) as usize),
+            incomplete: Some(read_u32(&response, 12) as usize),
+            warning_message: None,
+            peers: addrs
+                .into_iter()
+                .map(|addr| TrackerPeer {
+                    peer_id: None,
+                    addr,
+                })
+                .collect(),
+        })
+    }
+
+    /// Splits the hashes over as many requests as needed.
+    async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> TrackerResult<Vec<ScrapeStats>> {
+        let mut stats = Vec::new();
+
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            stats.extend(self.scrape_chunk(chunk).await?);
+        }
+
+        Ok(stats)
+    }
+}
+
+/// The IP address field only carries IPv4, anything else asks the tracker to use the sender address.
+fn ip_value(ip: Option<IpAddr>) -> u32 {
+    match ip {
+        Some(IpAddr::V4(ip)) => ip.into(),
+        _ => 0,
+    }
+}
+
+/// -1 leaves the number of peers to the tracker.
+fn num_want_value(numwant: Option<usize>) -> i32 {
+    numwant.map_or(-1, |n| n.min(i32::MAX as usize) as i32)
+}
+
+impl TrackerEvent {
+    fn udp_value(&self) -> u32 {
+        match self {
+            Self::Empty => 0,
+            Self::Completed => 1,
+            Self::Started => 2,
+            Self::Stopped => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::sample_meta;
+    use crate::tracker::{AnnounceOptions, Tracker};
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    /// Answers connect, announce and scrape requests, ignoring the first `drop` datagrams.
+    async fn fake_tracker(mut drop: usize) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 2048];
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = &buffer[..len];
+
+                if drop > 0 {
+                    drop -= 1;
+                    continue;
+                }
+
+                let action = read_u32(request, 8);
+                let transaction_id = &request[12..16];
+                let mut response = Vec::new();
+                response.extend(action.to_be_bytes());
+                response.extend(transaction_id);
+
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(&request[..8], PROTOCOL_ID.to_be_bytes());
+                        response.extend(CONNECTION_ID.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(&request[..8], CONNECTION_ID.to_be_bytes());
+                        assert_eq!(len, 98);
+                        assert_eq!(read_u32(request, 80), 2); // started
+                        assert_eq!(request[84..88], [10, 0, 0, 2]);
+                        assert_eq!(read_u32(request, 88), 0xdeadbeef);
+                        assert_eq!(read_u32(request, 92), 50);
+                        assert_eq!(request[96..98], 6881_u16.to_be_bytes());
+                        response.extend(1800_u32.to_be_bytes());
+                        response.extend(4_u32.to_be_bytes());
+                        response.extend(7_u32.to_be_bytes());
+                        response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+                    }
+                    ACTION_SCRAPE => {
+                        for (i, _) in request[16..].chunks_exact(20).enumerate() {
+                            response.extend((i as u32 + 1).to_be_bytes());
+                            response.extend(5_u32.to_be_bytes());
+                            response.extend(9_u32.to_be_bytes());
+                        }
+                    }
+                    _ => {
+                        let mut error = ACTION_ERROR.to_be_bytes().to_vec();
+                        error.extend(transaction_id);
+                        error.extend(b"bad action");
+                        response = error;
+                    }
+                }
+
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn udp_announce_1() {
+        let meta = sample_meta();
+        let mut tracker = UdpTracker::connect_addr(fake_tracker(0).await)
+            .await
+            .unwrap();
+
+        let details = TrackerDetails {
+            meta: &meta,
+            peer_id: *b"abcdefghijklmnopqrst",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 4,
+            event: TrackerEvent::Started,
+            tracker_id: None,
+            options: AnnounceOptions {
+                key: 0xdeadbeef,
+                numwant: Some(50),
+                ip: Some("10.0.0.2".parse().unwrap()),
+                no_peer_id: false,
+                supportcrypto: false,
+            },
+        };
+
+        let response = tracker.announce(&details).await.unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.incomplete, Some(4));
+        assert_eq!(response.complete, Some(7));
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr, "10.0.0.1:6881".parse().unwrap());
+        assert_eq!(tracker.connection.map(|(id, _)| id), Some(CONNECTION_ID));
+    }
+
+    #[tokio::test]
+    async fn udp_scrape_1() {
+        let mut tracker = UdpTracker::connect_addr(fake_tracker(0).await)
+            .await
+            .unwrap();
+
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 1,
+                    downloaded: 5,
+                    incomplete: 9,
+                },
+                ScrapeStats {
+                    complete: 2,
+                    downloaded: 5,
+                    incomplete: 9,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_scrape_many() {
+        let mut tracker = UdpTracker::connect_addr(fake_tracker(0).await)
+            .await
+            .unwrap();
+
+        let hashes = vec![[0; 20]; MAX_SCRAPE_HASHES + 2];
+        let stats = tracker.scrape(&hashes).await.unwrap();
+
+        assert_eq!(stats.len(), MAX_SCRAPE_HASHES + 2);
+        assert_eq!(stats[MAX_SCRAPE_HASHES - 1].complete, MAX_SCRAPE_HASHES);
+        assert_eq!(stats[MAX_SCRAPE_HASHES + 1].complete, 2);
+    }
+
+    #[tokio::test]
+    async fn udp_retransmit() {
+        let mut tracker = UdpTracker::connect_addr(fake_tracker(2).await)
+            .await
+            .unwrap();
+        tracker.timeout = Duration::from_millis(20);
+
+        assert_eq!(tracker.connection_id().await.unwrap(), CONNECTION_ID);
+    }
+
+    #[tokio::test]
+    async fn udp_timeout() {
+        let mut tracker = UdpTracker::connect_addr(fake_tracker(usize::MAX).await)
+            .await
+            .unwrap();
+        tracker.timeout = Duration::from_millis(5);
+        tracker.max_retries = 2;
+
+        let error = tracker.connection_id().await.unwrap_err();
+
+        assert!(matches!(error, TrackerError::Io(e) if e.kind() == ErrorKind::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn udp_error_action() {
+        let mut tracker = UdpTracker::connect_addr(fake_tracker(0).await)
+            .await
+            .unwrap();
+
+        let mut request = CONNECTION_ID.to_be_bytes().to_vec();
+        request.extend(9_u32.to_be_bytes());
+        request.extend(7_u32.to_be_bytes());
+
+        let error = tracker.exchange(&request, 9, 7, 8).await.unwrap_err();
+
+        assert!(matches!(error, TrackerError::Failure(m) if m == "bad action"));
+    }
+
+    #[tokio::test]
+    async fn udp_connect_bad_url() {
+        let error = UdpTracker::connect("http://example.com/announce")
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}